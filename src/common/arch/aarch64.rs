@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Result};
+use nix::{sys, unistd::Pid};
+use syscalls::Sysno;
+
+pub const NAME: &str = "aarch64";
+
+/// `svc #0` (0xd4000001), little-endian
+pub const SYSCALL_BYTES: [u8; 4] = [0x01, 0x00, 0x00, 0xd4];
+
+pub const INSTRUCTION_ALIGNMENT: usize = 4;
+
+/// EM_AARCH64
+pub const ELF_MACHINE: u16 = 183;
+
+/// body of the restorer executable (see `restorer`): `mov x8, #94; mov x0, #1; svc #0`, i.e.
+/// exit_group(1) should it ever run without us
+pub const RESTORER_CODE: [u8; 12] = [
+    0xc8, 0x0b, 0x80, 0xd2, 0x20, 0x00, 0x80, 0xd2, 0x01, 0x00, 0x00, 0xd4,
+];
+
+/// what pid 1 of a pid namespace made for a thaw runs: reaps children until there are none left,
+/// then exits
+pub const REAPER_CODE: [u8; 44] = [
+    0x00, 0x00, 0x80, 0x92, // mov x0, #-1
+    0x01, 0x00, 0x80, 0xd2, // mov x1, #0
+    0x02, 0x00, 0x80, 0xd2, // mov x2, #0
+    0x03, 0x00, 0x80, 0xd2, // mov x3, #0
+    0x88, 0x20, 0x80, 0xd2, // mov x8, #260 (wait4)
+    0x01, 0x00, 0x00, 0xd4, // svc #0
+    0x1f, 0x28, 0x00, 0xb1, // cmn x0, #ECHILD
+    0x21, 0xff, 0xff, 0x54, // b.ne 0
+    0x00, 0x00, 0x80, 0xd2, // mov x0, #0
+    0xc8, 0x0b, 0x80, 0xd2, // mov x8, #94 (exit_group)
+    0x01, 0x00, 0x00, 0xd4, // svc #0
+];
+
+/// `ldr x16, #8; br x16; .quad target`; x16 is the intra-procedure-call scratch register, so this
+/// can replace the start of any function
+pub fn jump_to(target: u64) -> Vec<u8> {
+    let mut r = vec![0x50, 0x00, 0x00, 0x58, 0x00, 0x02, 0x1f, 0xd6];
+    r.extend_from_slice(&target.to_le_bytes());
+    r
+}
+
+pub const FP_REGSET: libc::c_int = libc::NT_PRFPREG;
+
+/// size of `struct user_fpsimd_state` from <asm/ptrace.h>: V0-V31, FPSR, FPCR and padding
+pub const FP_REGISTERS_SIZE: usize = 528;
+
+pub const MAX_FP_REGISTERS_SIZE: usize = FP_REGISTERS_SIZE;
+
+pub const PRFPREG_SIZE: usize = FP_REGISTERS_SIZE;
+
+pub fn fp_registers_size() -> usize {
+    FP_REGISTERS_SIZE
+}
+
+// from <linux/elf.h>, not exported by libc
+const NT_ARM_TLS: libc::c_int = 0x401;
+
+pub const REGISTER_NAMES: [&str; 35] = [
+    "x0",
+    "x1",
+    "x2",
+    "x3",
+    "x4",
+    "x5",
+    "x6",
+    "x7",
+    "x8",
+    "x9",
+    "x10",
+    "x11",
+    "x12",
+    "x13",
+    "x14",
+    "x15",
+    "x16",
+    "x17",
+    "x18",
+    "x19",
+    "x20",
+    "x21",
+    "x22",
+    "x23",
+    "x24",
+    "x25",
+    "x26",
+    "x27",
+    "x28",
+    "x29",
+    "x30",
+    "sp",
+    "pc",
+    "pstate",
+    "tpidr_el0",
+];
+
+#[derive(Clone, Copy)]
+pub struct Registers {
+    raw: libc::user_regs_struct,
+    // the thread pointer isn't part of NT_PRSTATUS on ARM64, it has a regset of its own
+    tpidr_el0: u64,
+}
+
+impl Registers {
+    pub fn pc(&self) -> u64 {
+        self.raw.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.raw.pc = pc;
+    }
+
+    pub fn sp(&self) -> u64 {
+        self.raw.sp
+    }
+
+    pub fn thread_pointer(&self) -> u64 {
+        self.tpidr_el0
+    }
+
+    // syscall number in x8, args in x0, x1, x2, x3...
+
+    /// returns the syscall number at a syscall-enter-stop
+    pub fn syscall_number(&self) -> u64 {
+        self.raw.regs[8]
+    }
+
+    pub fn arg(&self, i: usize) -> u64 {
+        self.raw.regs[arg_register(i)]
+    }
+
+    pub fn set_arg(&mut self, i: usize, value: u64) {
+        self.raw.regs[arg_register(i)] = value;
+    }
+
+    // the return value overwrites the first argument in x0
+    pub fn return_value(&self) -> u64 {
+        self.raw.regs[0]
+    }
+
+    pub fn set_return_value(&mut self, value: u64) {
+        self.raw.regs[0] = value;
+    }
+
+    /// returns the address of the syscall instruction if the tracee was stopped while blocked in
+    /// a syscall
+    ///
+    /// the kernel rewinds PC to the `svc` before stopping so that the syscall can be restarted
+    pub fn interrupted_syscall_address(&self) -> u64 {
+        self.raw.pc
+    }
+
+    /// sets up the registers so that single-stepping from `pc` executes the given syscall
+    pub fn prepare_syscall(&mut self, sysno: Sysno, args: &[u64], pc: u64) {
+        self.raw.regs[8] = sysno.id() as u64;
+        for (i, arg) in args.iter().enumerate() {
+            self.raw.regs[i] = *arg;
+        }
+        self.raw.pc = pc;
+    }
+
+    /// `elf_gregset_t`, as found in the NT_PRSTATUS notes of core files; x0-x30, sp, pc and
+    /// pstate, but not the thread pointer
+    pub fn to_elf_gregset(self) -> Vec<u8> {
+        super::registers_to_words(&self.raw)
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    }
+
+    /// in the order of `REGISTER_NAMES`
+    pub(super) fn to_words(self) -> Vec<u64> {
+        let mut words = super::registers_to_words(&self.raw);
+        words.push(self.tpidr_el0);
+        words
+    }
+
+    pub(super) fn from_words(words: &[u64]) -> Result<Self> {
+        let (tpidr_el0, gp) = words.split_last().ok_or(anyhow!(
+            "expected {} registers but got none",
+            REGISTER_NAMES.len()
+        ))?;
+        Ok(Self {
+            raw: super::registers_from_words(gp)?,
+            tpidr_el0: *tpidr_el0,
+        })
+    }
+}
+
+/// the register holding syscall argument `i`, which is just x<i>, but checked as on x86_64
+fn arg_register(i: usize) -> usize {
+    if i >= 6 {
+        panic!("syscalls take at most 6 arguments, got index {}", i);
+    }
+    i
+}
+
+pub fn get_registers(pid: Pid) -> Result<Registers> {
+    let raw = sys::ptrace::getregset::<sys::ptrace::regset::NT_PRSTATUS>(pid)
+        .map_err(|e| anyhow!("PTRACE_GETREGSET failed: {}", e))?;
+
+    let mut tpidr_el0: u64 = 0;
+    let mut iov = libc::iovec {
+        iov_base: &mut tpidr_el0 as *mut u64 as *mut libc::c_void,
+        iov_len: std::mem::size_of::<u64>(),
+    };
+    unsafe {
+        syscalls::syscall!(
+            Sysno::ptrace,
+            libc::PTRACE_GETREGSET,
+            pid.as_raw(),
+            NT_ARM_TLS,
+            &mut iov as *mut _
+        )
+    }
+    .map_err(|e| anyhow!("PTRACE_GETREGSET failed (NT_ARM_TLS): {}", e))?;
+
+    Ok(Registers { raw, tpidr_el0 })
+}
+
+pub fn set_registers(pid: Pid, registers: &Registers) -> Result<()> {
+    sys::ptrace::setregset::<sys::ptrace::regset::NT_PRSTATUS>(pid, registers.raw)
+        .map_err(|e| anyhow!("PTRACE_SETREGSET failed: {}", e))?;
+
+    let mut tpidr_el0 = registers.tpidr_el0;
+    let mut iov = libc::iovec {
+        iov_base: &mut tpidr_el0 as *mut u64 as *mut libc::c_void,
+        iov_len: std::mem::size_of::<u64>(),
+    };
+    unsafe {
+        syscalls::syscall!(
+            Sysno::ptrace,
+            libc::PTRACE_SETREGSET,
+            pid.as_raw(),
+            NT_ARM_TLS,
+            &mut iov as *mut _
+        )
+    }
+    .map_err(|e| anyhow!("PTRACE_SETREGSET failed (NT_ARM_TLS): {}", e))?;
+
+    Ok(())
+}
@@ -0,0 +1,337 @@
+// Everything that differs between instruction sets when injecting syscalls into a tracee lives
+// here. Each submodule exposes the same constants, functions and `Registers` type, and the one
+// matching the target we are compiled for is re-exported.
+
+use std::{collections::BTreeMap, fs};
+
+use anyhow::{anyhow, Result};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use syscalls::Sysno;
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::*;
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::*;
+
+#[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+compile_error!("process_magic only supports aarch64 and x86_64");
+
+// bump whenever the way registers are serialized changes
+pub const REGISTER_LAYOUT_VERSION: u32 = 4;
+
+/// registers keyed by name (e.g., "x8" or "rip"), which is how `Registers` is serialized so that
+/// saved state doesn't depend on the layout of `libc::user_regs_struct`
+pub type NamedRegisters = BTreeMap<String, u64>;
+
+impl From<Registers> for NamedRegisters {
+    fn from(registers: Registers) -> Self {
+        REGISTER_NAMES
+            .iter()
+            .zip(registers.to_words())
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+}
+
+impl TryFrom<NamedRegisters> for Registers {
+    type Error = anyhow::Error;
+
+    fn try_from(named: NamedRegisters) -> Result<Self> {
+        let mut words = Vec::with_capacity(REGISTER_NAMES.len());
+        for name in REGISTER_NAMES {
+            let value = named.get(name).ok_or(anyhow!(
+                "register {} is missing (expected {} registers)",
+                name,
+                NAME
+            ))?;
+            words.push(*value);
+        }
+        Registers::from_words(&words)
+    }
+}
+
+impl Serialize for Registers {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        NamedRegisters::from(*self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Registers {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let named = NamedRegisters::deserialize(deserializer)?;
+        Registers::try_from(named).map_err(serde::de::Error::custom)
+    }
+}
+
+/// the floating-point/SIMD register set exactly as PTRACE_GETREGSET returns it for `FP_REGSET`
+///
+/// on x86_64 this is the XSAVE area, whose size depends on the CPU; on ARM64 it includes FPSR and
+/// FPCR, and the thread pointer is part of `Registers` instead
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "Vec<u8>", into = "Vec<u8>")]
+pub struct FpRegisters {
+    data: Vec<u8>,
+}
+
+impl FpRegisters {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl TryFrom<Vec<u8>> for FpRegisters {
+    type Error = anyhow::Error;
+
+    fn try_from(data: Vec<u8>) -> Result<Self> {
+        if data.len() < FP_REGISTERS_SIZE || data.len() > MAX_FP_REGISTERS_SIZE {
+            return Err(anyhow!(
+                "expected {}-{} bytes of floating-point register data for {} but got {}",
+                FP_REGISTERS_SIZE,
+                MAX_FP_REGISTERS_SIZE,
+                NAME,
+                data.len()
+            ));
+        }
+        Ok(Self { data })
+    }
+}
+
+impl From<FpRegisters> for Vec<u8> {
+    fn from(fp_registers: FpRegisters) -> Self {
+        fp_registers.data
+    }
+}
+
+pub fn get_fp_registers(pid: Pid) -> Result<FpRegisters> {
+    let mut data = vec![0u8; fp_registers_size()];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    unsafe {
+        syscalls::syscall!(
+            Sysno::ptrace,
+            libc::PTRACE_GETREGSET,
+            pid.as_raw(),
+            FP_REGSET,
+            &mut iov as *mut _
+        )
+    }
+    .map_err(|e| anyhow!("PTRACE_GETREGSET failed ({:#x}): {}", FP_REGSET, e))?;
+
+    // the kernel shrinks iov_len to what it actually wrote
+    FpRegisters::try_from(data[..iov.iov_len].to_vec())
+}
+
+pub fn set_fp_registers(pid: Pid, fp_registers: &FpRegisters) -> Result<()> {
+    // the kernel only takes exactly as much as it hands out, which for an XSAVE area taken on
+    // another CPU may be more or less; the header says which parts are in use, so padding with
+    // zeros is safe, and the kernel refuses a truncated area that used features it doesn't have
+    let mut data = fp_registers.data.clone();
+    data.resize(get_fp_registers(pid)?.data.len(), 0);
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    unsafe {
+        syscalls::syscall!(
+            Sysno::ptrace,
+            libc::PTRACE_SETREGSET,
+            pid.as_raw(),
+            FP_REGSET,
+            &mut iov as *mut _
+        )
+    }
+    .map_err(|e| anyhow!("PTRACE_SETREGSET failed ({:#x}): {}", FP_REGSET, e))?;
+
+    Ok(())
+}
+
+/// describes the machine a process was captured on
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MachineInfo {
+    pub arch: String,
+    pub kernel_version: String,
+    pub page_size: u64,
+    pub register_layout_version: u32,
+}
+
+impl MachineInfo {
+    pub fn current() -> Result<Self> {
+        let kernel_version = fs::read_to_string("/proc/sys/kernel/osrelease")
+            .map_err(|e| anyhow!("could not read kernel version: {}", e))?;
+        Ok(Self {
+            arch: NAME.to_string(),
+            kernel_version: kernel_version.trim().to_string(),
+            page_size: procfs::page_size(),
+            register_layout_version: REGISTER_LAYOUT_VERSION,
+        })
+    }
+
+    /// returns an error if a process captured on `self` cannot be restored on `host`
+    ///
+    /// the kernel version is informational only: different kernels can run the same process as
+    /// long as the instruction set and memory layout agree
+    pub fn check_compatible(&self, host: &MachineInfo) -> Result<()> {
+        if self.arch != host.arch {
+            return Err(anyhow!(
+                "process was captured on {} but this machine is {}",
+                self.arch,
+                host.arch
+            ));
+        }
+
+        if self.page_size != host.page_size {
+            return Err(anyhow!(
+                "process was captured with a page size of {} but this machine uses {}",
+                self.page_size,
+                host.page_size
+            ));
+        }
+
+        if self.register_layout_version != host.register_layout_version {
+            return Err(anyhow!(
+                "register data is in layout version {} but this build expects version {}",
+                self.register_layout_version,
+                host.register_layout_version
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// returns the offset of the first syscall instruction in `buffer`
+pub fn find_syscall_instruction(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(SYSCALL_BYTES.len())
+        .enumerate()
+        .step_by(INSTRUCTION_ALIGNMENT)
+        .find(|(_, window)| *window == SYSCALL_BYTES)
+        .map(|(i, _)| i)
+}
+
+/// overwrites the start of a word read with PTRACE_PEEKDATA with the syscall instruction
+pub fn with_syscall_instruction(word: i64) -> i64 {
+    let mut bytes = word.to_le_bytes();
+    bytes[..SYSCALL_BYTES.len()].copy_from_slice(&SYSCALL_BYTES);
+    i64::from_le_bytes(bytes)
+}
+
+/// returns the raw contents of `libc::user_regs_struct`, which on every supported architecture is
+/// just a sequence of 64-bit registers
+fn registers_to_words(regs: &libc::user_regs_struct) -> Vec<u64> {
+    let n = std::mem::size_of::<libc::user_regs_struct>() / std::mem::size_of::<u64>();
+    let p = regs as *const libc::user_regs_struct as *const u64;
+    unsafe { std::slice::from_raw_parts(p, n) }.to_vec()
+}
+
+fn registers_from_words(words: &[u64]) -> Result<libc::user_regs_struct> {
+    let n = std::mem::size_of::<libc::user_regs_struct>() / std::mem::size_of::<u64>();
+    if words.len() != n {
+        return Err(anyhow!(
+            "expected {} registers for {} but got {}",
+            n,
+            NAME,
+            words.len()
+        ));
+    }
+
+    let mut regs = std::mem::MaybeUninit::<libc::user_regs_struct>::uninit();
+    unsafe {
+        std::ptr::copy_nonoverlapping(words.as_ptr(), regs.as_mut_ptr() as *mut u64, n);
+        Ok(regs.assume_init())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_syscall_instruction() {
+        let mut buffer = vec![0u8; 64];
+        assert_eq!(find_syscall_instruction(&buffer), None);
+
+        buffer[40..40 + SYSCALL_BYTES.len()].copy_from_slice(&SYSCALL_BYTES);
+        assert_eq!(find_syscall_instruction(&buffer), Some(40));
+    }
+
+    #[test]
+    fn test_check_compatible() {
+        let host = MachineInfo {
+            arch: "aarch64".to_string(),
+            kernel_version: "6.8.0-40-generic".to_string(),
+            page_size: 4096,
+            register_layout_version: REGISTER_LAYOUT_VERSION,
+        };
+
+        let other_kernel = MachineInfo {
+            kernel_version: "6.1.0".to_string(),
+            ..host.clone()
+        };
+        assert!(other_kernel.check_compatible(&host).is_ok());
+
+        let other_arch = MachineInfo {
+            arch: "x86_64".to_string(),
+            ..host.clone()
+        };
+        assert!(other_arch.check_compatible(&host).is_err());
+
+        let other_page_size = MachineInfo {
+            page_size: 16384,
+            ..host.clone()
+        };
+        assert!(other_page_size.check_compatible(&host).is_err());
+
+        let other_layout = MachineInfo {
+            register_layout_version: REGISTER_LAYOUT_VERSION + 1,
+            ..host.clone()
+        };
+        assert!(other_layout.check_compatible(&host).is_err());
+    }
+
+    #[test]
+    fn test_named_registers_round_trip() {
+        let words: Vec<u64> = (0..REGISTER_NAMES.len() as u64).collect();
+        let registers = Registers::from_words(&words).unwrap();
+
+        let named = NamedRegisters::from(registers);
+        assert_eq!(named.len(), REGISTER_NAMES.len());
+        assert_eq!(named[REGISTER_NAMES[1]], 1);
+
+        let round_tripped = Registers::try_from(named.clone()).unwrap();
+        assert_eq!(round_tripped.pc(), registers.pc());
+        assert_eq!(round_tripped.to_words(), words);
+
+        let mut incomplete = named;
+        incomplete.remove(REGISTER_NAMES[0]);
+        assert!(Registers::try_from(incomplete).is_err());
+    }
+
+    #[test]
+    fn test_fp_registers_size() {
+        assert!(FpRegisters::try_from(vec![0u8; FP_REGISTERS_SIZE]).is_ok());
+        assert!(FpRegisters::try_from(vec![0u8; FP_REGISTERS_SIZE - 8]).is_err());
+        assert!(FpRegisters::try_from(vec![0u8; MAX_FP_REGISTERS_SIZE + 8]).is_err());
+        assert!(fp_registers_size() >= FP_REGISTERS_SIZE);
+
+        // a bad length has to be caught when loading saved state, not when calling ptrace()
+        let json = serde_json::to_string(&vec![0u8; 16]).unwrap();
+        assert!(serde_json::from_str::<FpRegisters>(&json).is_err());
+    }
+
+    #[test]
+    fn test_with_syscall_instruction() {
+        let word = with_syscall_instruction(-1);
+        let bytes = word.to_le_bytes();
+        assert_eq!(&bytes[..SYSCALL_BYTES.len()], &SYSCALL_BYTES[..]);
+        assert!(bytes[SYSCALL_BYTES.len()..].iter().all(|b| *b == 0xff));
+    }
+}
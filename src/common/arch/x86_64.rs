@@ -0,0 +1,188 @@
+use anyhow::{anyhow, Result};
+use nix::{sys, unistd::Pid};
+use syscalls::Sysno;
+
+pub const NAME: &str = "x86_64";
+
+/// `syscall` (0x0f 0x05)
+pub const SYSCALL_BYTES: [u8; 2] = [0x0f, 0x05];
+
+pub const INSTRUCTION_ALIGNMENT: usize = 1;
+
+/// EM_X86_64
+pub const ELF_MACHINE: u16 = 62;
+
+/// body of the restorer executable (see `restorer`): `mov eax, 231; mov edi, 1; syscall`, i.e.
+/// exit_group(1) should it ever run without us
+pub const RESTORER_CODE: [u8; 12] = [
+    0xb8, 0xe7, 0x00, 0x00, 0x00, 0xbf, 0x01, 0x00, 0x00, 0x00, 0x0f, 0x05,
+];
+
+/// what pid 1 of a pid namespace made for a thaw runs: reaps children until there are none left,
+/// then exits
+pub const REAPER_CODE: [u8; 36] = [
+    0xb8, 0x3d, 0x00, 0x00, 0x00, // mov eax, 61 (wait4)
+    0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff, // mov rdi, -1
+    0x31, 0xf6, // xor esi, esi
+    0x31, 0xd2, // xor edx, edx
+    0x45, 0x31, 0xd2, // xor r10d, r10d
+    0x0f, 0x05, // syscall
+    0x48, 0x83, 0xf8, 0xf6, // cmp rax, -ECHILD
+    0x75, 0xe5, // jne 0
+    0xb8, 0xe7, 0x00, 0x00, 0x00, // mov eax, 231 (exit_group)
+    0x31, 0xff, // xor edi, edi
+    0x0f, 0x05, // syscall
+];
+
+/// `movabs rax, target; jmp rax`; rax doesn't carry arguments, so this can replace the start of
+/// any function
+pub fn jump_to(target: u64) -> Vec<u8> {
+    let mut r = vec![0x48, 0xb8];
+    r.extend_from_slice(&target.to_le_bytes());
+    r.extend_from_slice(&[0xff, 0xe0]);
+    r
+}
+
+/// NT_X86_XSTATE: the whole XSAVE area, with the AVX and AVX-512 registers as well as x87 and SSE
+pub const FP_REGSET: libc::c_int = 0x202;
+
+/// the smallest XSAVE area, which is the FXSAVE area (x87, MXCSR and XMM0-XMM15) and the XSAVE
+/// header; the rest depends on the CPU, see `fp_registers_size`
+pub const FP_REGISTERS_SIZE: usize = 576;
+
+/// XSAVE areas are a few KiB, about 11 KiB with AMX
+pub const MAX_FP_REGISTERS_SIZE: usize = 1 << 16;
+
+/// size of `struct user_fpregs_struct`, i.e. the FXSAVE area, which is what NT_PRFPREG notes in
+/// core files hold
+pub const PRFPREG_SIZE: usize = 512;
+
+/// how big an XSAVE area this CPU can need
+pub fn fp_registers_size() -> usize {
+    // CPUID leaf 0xd: EBX is the size for the features enabled in XCR0, ECX for all of them
+    // __cpuid_count() is only unsafe on older compilers
+    #[allow(unused_unsafe)]
+    let cpuid = unsafe { std::arch::x86_64::__cpuid_count(0xd, 0) };
+    (cpuid.ebx.max(cpuid.ecx) as usize).clamp(FP_REGISTERS_SIZE, MAX_FP_REGISTERS_SIZE)
+}
+
+pub const REGISTER_NAMES: [&str; 27] = [
+    "r15", "r14", "r13", "r12", "rbp", "rbx", "r11", "r10", "r9", "r8", "rax", "rcx", "rdx", "rsi",
+    "rdi", "orig_rax", "rip", "cs", "eflags", "rsp", "ss", "fs_base", "gs_base", "ds", "es", "fs",
+    "gs",
+];
+
+#[derive(Clone, Copy)]
+pub struct Registers {
+    raw: libc::user_regs_struct,
+}
+
+impl Registers {
+    pub fn pc(&self) -> u64 {
+        self.raw.rip
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.raw.rip = pc;
+    }
+
+    pub fn sp(&self) -> u64 {
+        self.raw.rsp
+    }
+
+    pub fn thread_pointer(&self) -> u64 {
+        self.raw.fs_base
+    }
+
+    // syscall number in rax, args in rdi, rsi, rdx, r10, r8, r9
+
+    /// returns the syscall number at a syscall-enter-stop
+    ///
+    /// by then the kernel has already clobbered rax with -ENOSYS, so we read the saved copy
+    pub fn syscall_number(&self) -> u64 {
+        self.raw.orig_rax
+    }
+
+    pub fn arg(&self, i: usize) -> u64 {
+        match i {
+            0 => self.raw.rdi,
+            1 => self.raw.rsi,
+            2 => self.raw.rdx,
+            3 => self.raw.r10,
+            4 => self.raw.r8,
+            5 => self.raw.r9,
+            _ => panic!("syscalls take at most 6 arguments, got index {}", i),
+        }
+    }
+
+    pub fn set_arg(&mut self, i: usize, value: u64) {
+        match i {
+            0 => self.raw.rdi = value,
+            1 => self.raw.rsi = value,
+            2 => self.raw.rdx = value,
+            3 => self.raw.r10 = value,
+            4 => self.raw.r8 = value,
+            5 => self.raw.r9 = value,
+            _ => panic!("syscalls take at most 6 arguments, got index {}", i),
+        }
+    }
+
+    pub fn return_value(&self) -> u64 {
+        self.raw.rax
+    }
+
+    pub fn set_return_value(&mut self, value: u64) {
+        self.raw.rax = value;
+    }
+
+    /// returns the address of the syscall instruction if the tracee was stopped while blocked in
+    /// a syscall
+    ///
+    /// unlike on ARM64, RIP is left pointing just past the `syscall` instruction
+    pub fn interrupted_syscall_address(&self) -> u64 {
+        self.raw.rip - SYSCALL_BYTES.len() as u64
+    }
+
+    /// sets up the registers so that single-stepping from `pc` executes the given syscall
+    pub fn prepare_syscall(&mut self, sysno: Sysno, args: &[u64], pc: u64) {
+        self.raw.rax = sysno.id() as u64;
+        // otherwise, if the tracee was blocked in a syscall, the kernel may try to restart it when
+        // we resume and rewind RIP from under us
+        self.raw.orig_rax = u64::MAX;
+        for (i, arg) in args.iter().enumerate() {
+            self.set_arg(i, *arg);
+        }
+        self.raw.rip = pc;
+    }
+
+    /// `elf_gregset_t`, as found in the NT_PRSTATUS notes of core files
+    pub fn to_elf_gregset(self) -> Vec<u8> {
+        super::registers_to_words(&self.raw)
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    }
+
+    /// in the order of `REGISTER_NAMES`
+    pub(super) fn to_words(self) -> Vec<u64> {
+        super::registers_to_words(&self.raw)
+    }
+
+    pub(super) fn from_words(words: &[u64]) -> Result<Self> {
+        Ok(Self {
+            raw: super::registers_from_words(words)?,
+        })
+    }
+}
+
+pub fn get_registers(pid: Pid) -> Result<Registers> {
+    let raw = sys::ptrace::getregset::<sys::ptrace::regset::NT_PRSTATUS>(pid)
+        .map_err(|e| anyhow!("PTRACE_GETREGSET failed: {}", e))?;
+    Ok(Registers { raw })
+}
+
+pub fn set_registers(pid: Pid, registers: &Registers) -> Result<()> {
+    sys::ptrace::setregset::<sys::ptrace::regset::NT_PRSTATUS>(pid, registers.raw)
+        .map_err(|e| anyhow!("PTRACE_SETREGSET failed: {}", e))?;
+    Ok(())
+}
@@ -0,0 +1,290 @@
+// Just enough of ELF64 to write the restorer executable (see `restorer`) and core files, to find
+// the build-id of a mapped file and to list the functions a vDSO exports. Everything is
+// little-endian, as are both of the architectures we support.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use anyhow::{anyhow, Result};
+
+pub const EHDR_SIZE: usize = 64;
+pub const PHDR_SIZE: usize = 56;
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_CORE: u16 = 4;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_NOTE: u32 = 4;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const NT_GNU_BUILD_ID: u32 = 3;
+
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const SHT_DYNSYM: u32 = 11;
+const STT_FUNC: u8 = 2;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+
+/// returns an ELF header for this machine, with `phnum` program headers right after it and no
+/// section headers
+pub fn file_header(e_type: u16, entry: u64, phnum: u16) -> Vec<u8> {
+    let mut r = Vec::with_capacity(EHDR_SIZE);
+    // e_ident: ELFCLASS64, ELFDATA2LSB, EV_CURRENT, ELFOSABI_SYSV
+    r.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    r.extend_from_slice(&e_type.to_le_bytes());
+    r.extend_from_slice(&crate::common::arch::ELF_MACHINE.to_le_bytes());
+    r.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    r.extend_from_slice(&entry.to_le_bytes());
+    r.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes()); // e_phoff
+    r.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    r.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    r.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    r.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    r.extend_from_slice(&phnum.to_le_bytes());
+    r.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+    r
+}
+
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut r = Vec::with_capacity(PHDR_SIZE);
+        r.extend_from_slice(&self.p_type.to_le_bytes());
+        r.extend_from_slice(&self.flags.to_le_bytes());
+        r.extend_from_slice(&self.offset.to_le_bytes());
+        r.extend_from_slice(&self.vaddr.to_le_bytes());
+        r.extend_from_slice(&self.vaddr.to_le_bytes()); // p_paddr
+        r.extend_from_slice(&self.filesz.to_le_bytes());
+        r.extend_from_slice(&self.memsz.to_le_bytes());
+        r.extend_from_slice(&self.align.to_le_bytes());
+        r
+    }
+}
+
+/// returns one entry of a PT_NOTE segment; the name and description are each padded to 4 bytes
+pub fn note(name: &str, n_type: u32, desc: &[u8]) -> Vec<u8> {
+    let mut r = Vec::new();
+    r.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+    r.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    r.extend_from_slice(&n_type.to_le_bytes());
+    r.extend_from_slice(name.as_bytes());
+    r.push(0);
+    pad_to_4(&mut r);
+    r.extend_from_slice(desc);
+    pad_to_4(&mut r);
+    r
+}
+
+fn pad_to_4(bytes: &mut Vec<u8>) {
+    bytes.resize(bytes.len().next_multiple_of(4), 0);
+}
+
+/// returns the GNU build-id of the ELF file at `path` in hex, or None if it isn't a 64-bit ELF file
+/// or doesn't have one
+pub fn read_build_id(path: &str) -> Result<Option<String>> {
+    let mut file = File::open(path)?;
+    let mut ehdr = [0u8; EHDR_SIZE];
+    if file.read_exact(&mut ehdr).is_err() || &ehdr[..5] != b"\x7fELF\x02" {
+        return Ok(None);
+    }
+    let phoff = u64::from_le_bytes(ehdr[32..40].try_into()?);
+    let phnum = u16::from_le_bytes(ehdr[56..58].try_into()?) as usize;
+
+    let mut phdrs = vec![0u8; PHDR_SIZE * phnum];
+    file.seek(SeekFrom::Start(phoff))?;
+    file.read_exact(&mut phdrs)?;
+    for phdr in phdrs.chunks_exact(PHDR_SIZE) {
+        if u32::from_le_bytes(phdr[..4].try_into()?) != PT_NOTE {
+            continue;
+        }
+        let offset = u64::from_le_bytes(phdr[8..16].try_into()?);
+        let filesz = u64::from_le_bytes(phdr[32..40].try_into()?);
+        let mut notes = vec![0u8; filesz as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut notes)?;
+        if let Some(id) = find_note(&notes, b"GNU\0", NT_GNU_BUILD_ID) {
+            return Ok(Some(id.iter().map(|b| format!("{:02x}", b)).collect()));
+        }
+    }
+    Ok(None)
+}
+
+/// returns the description of the first note in `notes` with the given name and type
+fn find_note<'a>(notes: &'a [u8], name: &[u8], n_type: u32) -> Option<&'a [u8]> {
+    let mut rest = notes;
+    while rest.len() >= 12 {
+        let word = |i: usize| u32::from_le_bytes(rest[i..i + 4].try_into().unwrap()) as usize;
+        let (namesz, descsz) = (word(0), word(4));
+        let desc_start = 12 + namesz.next_multiple_of(4);
+        let end = desc_start + descsz.next_multiple_of(4);
+        if rest.len() < desc_start + descsz {
+            return None;
+        }
+        if word(8) as u32 == n_type && &rest[12..12 + namesz] == name {
+            return Some(&rest[desc_start..desc_start + descsz]);
+        }
+        rest = &rest[end.min(rest.len())..];
+    }
+    None
+}
+
+/// a function exported by an ELF image that is mapped as a whole, such as the vDSO
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Symbol {
+    pub name: String,
+    /// from the start of the image
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// returns the global functions in the dynamic symbol table of `image`, sorted by name
+pub fn function_symbols(image: &[u8]) -> Result<Vec<Symbol>> {
+    if image.len() < EHDR_SIZE || &image[..5] != b"\x7fELF\x02" {
+        return Err(anyhow!("not a 64-bit ELF image"));
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([image[i], image[i + 1]]) as usize;
+    let phoff = u64_at(image, 32)? as usize;
+    let shoff = u64_at(image, 40)? as usize;
+    let (phnum, shnum) = (u16_at(56), u16_at(60));
+
+    // symbol values are addresses the image was linked at
+    let load = (0..phnum)
+        .filter_map(|i| phoff.checked_add(i * PHDR_SIZE))
+        .find(|&phdr| u32_at(image, phdr).ok() == Some(PT_LOAD))
+        .ok_or(anyhow!("no PT_LOAD segment"))?;
+    let (load_offset, load_vaddr) = (u64_at(image, load + 8)?, u64_at(image, load + 16)?);
+
+    let section = |i: usize| -> Result<(u32, &[u8], usize)> {
+        let outside = || anyhow!("section {} is outside of the image", i);
+        let shdr = shoff.checked_add(i * SHDR_SIZE).ok_or_else(outside)?;
+        let offset = u64_at(image, shdr.saturating_add(24))? as usize;
+        let size = u64_at(image, shdr.saturating_add(32))? as usize;
+        let end = offset.checked_add(size).ok_or_else(outside)?;
+        let data = image.get(offset..end).ok_or_else(outside)?;
+        Ok((
+            u32_at(image, shdr.saturating_add(4))?,
+            data,
+            u32_at(image, shdr.saturating_add(40))? as usize,
+        ))
+    };
+    let (symbols, link) = (0..shnum)
+        .map(section)
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .find(|(sh_type, _, _)| *sh_type == SHT_DYNSYM)
+        .map(|(_, data, link)| (data, link))
+        .ok_or(anyhow!("no dynamic symbol table"))?;
+    let (_, strings, _) = section(link)?;
+
+    let mut r = Vec::new();
+    for symbol in symbols.chunks_exact(SYM_SIZE) {
+        let (info, shndx) = (symbol[4], u16::from_le_bytes([symbol[6], symbol[7]]));
+        if info & 0xf != STT_FUNC || !matches!(info >> 4, STB_GLOBAL | STB_WEAK) || shndx == 0 {
+            continue;
+        }
+        let name_start = u32_at(symbol, 0)? as usize;
+        let name = strings
+            .get(name_start..)
+            .and_then(|s| s.split(|b| *b == 0).next())
+            .ok_or(anyhow!("symbol name is outside of the string table"))?;
+        let name = String::from_utf8_lossy(name).to_string();
+        let offset = u64_at(symbol, 8)?
+            .checked_sub(load_vaddr)
+            .and_then(|offset| offset.checked_add(load_offset))
+            .ok_or(anyhow!("{} is outside of the image", name))?;
+        r.push(Symbol {
+            name,
+            offset,
+            size: u64_at(symbol, 16)?,
+        });
+    }
+    r.sort();
+    Ok(r)
+}
+
+fn u32_at(bytes: &[u8], i: usize) -> Result<u32> {
+    let word = bytes
+        .get(i..i.saturating_add(4))
+        .ok_or(anyhow!("truncated ELF image"))?;
+    Ok(u32::from_le_bytes(word.try_into()?))
+}
+
+fn u64_at(bytes: &[u8], i: usize) -> Result<u64> {
+    let word = bytes
+        .get(i..i.saturating_add(8))
+        .ok_or(anyhow!("truncated ELF image"))?;
+    Ok(u64::from_le_bytes(word.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!(file_header(ET_CORE, 0, 1).len(), EHDR_SIZE);
+        let header = ProgramHeader {
+            p_type: PT_LOAD,
+            flags: PF_R,
+            offset: 0,
+            vaddr: 0,
+            filesz: 0,
+            memsz: 0,
+            align: 0,
+        };
+        assert_eq!(header.to_bytes().len(), PHDR_SIZE);
+    }
+
+    #[test]
+    fn test_read_build_id() {
+        let notes = [
+            note("CORE", NT_GNU_BUILD_ID, &[1, 2]),
+            note("GNU", NT_GNU_BUILD_ID, &[0xab, 0xcd, 0xef]),
+        ]
+        .concat();
+        let mut file = file_header(ET_EXEC, 0, 1);
+        let header = ProgramHeader {
+            p_type: PT_NOTE,
+            flags: PF_R,
+            offset: (EHDR_SIZE + PHDR_SIZE) as u64,
+            vaddr: 0,
+            filesz: notes.len() as u64,
+            memsz: 0,
+            align: 4,
+        };
+        file.extend_from_slice(&header.to_bytes());
+        file.extend_from_slice(&notes);
+
+        let path = std::env::temp_dir().join(format!("proctool-build-id-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, &file).unwrap();
+        assert_eq!(read_build_id(path).unwrap().as_deref(), Some("abcdef"));
+        std::fs::write(path, b"#!/bin/sh\n").unwrap();
+        assert_eq!(read_build_id(path).unwrap(), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_note_padding() {
+        let note = note("CORE", 1, &[1, 2, 3]);
+        // 12 byte header, "CORE\0" padded to 8, 3 bytes of description padded to 4
+        assert_eq!(note.len(), 12 + 8 + 4);
+        assert_eq!(&note[12..17], b"CORE\0");
+        assert_eq!(&note[20..24], &[1, 2, 3, 0]);
+    }
+}
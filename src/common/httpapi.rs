@@ -1,18 +1,47 @@
 use serde::{Deserialize, Serialize};
 
-use crate::teleclient::myprocfs::MemoryMap;
+use crate::{
+    common::{
+        arch::{MachineInfo, NamedRegisters},
+        signals::{QueuedSignal, SignalState},
+    },
+    teleclient::myprocfs::MemoryMap,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TeleforkApiRequest {
-    // TODO: include instruction set (x86-64 or arm)
-    // https://doc.rust-lang.org/reference/conditional-compilation.html
-    // unstructured and processor-dependent; only intended to be passed back to ptrace()
-    pub gp_register_data: Vec<u8>,
+    pub machine: MachineInfo,
+    // left untyped so that a request from another architecture still deserializes and can be
+    // rejected by the compatibility check
+    pub registers: NamedRegisters,
+    // see `FpRegisters`; untyped for the same reason as `registers`
     pub fp_register_data: Vec<u8>,
     pub memory_maps: Vec<MemoryMap>,
+    pub signals: SignalState,
+    /// the signals the thread blocks; see `signals`
+    pub signal_mask: u64,
+    /// signals queued for the thread in particular rather than for the process as a whole
+    pub thread_pending_signals: Vec<QueuedSignal>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TeleforkApiResponse {
     pub success: bool,
+    pub error: Option<String>,
+}
+
+impl TeleforkApiResponse {
+    pub fn ok() -> Self {
+        Self {
+            success: true,
+            error: None,
+        }
+    }
+
+    pub fn error(message: String) -> Self {
+        Self {
+            success: false,
+            error: Some(message),
+        }
+    }
 }
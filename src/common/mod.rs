@@ -1 +1,6 @@
+pub mod arch;
+pub mod elf;
 pub mod httpapi;
+pub mod restorer;
+pub mod signals;
+pub mod vdso;
@@ -0,0 +1,318 @@
+// Thawed processes start out as this tiny executable instead of as a fork of ourselves, so that
+// apart from the vDSO there is nothing left in the address space for the image to collide with.
+// The executable is assembled here and run from a memfd, so there is nothing extra to install.
+
+use std::{
+    ffi::CString,
+    fs::{self, File},
+    io::Write,
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::fs::MetadataExt,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use nix::{
+    sys::{
+        self,
+        memfd::MemFdCreateFlag,
+        signal::Signal,
+        wait::{WaitPidFlag, WaitStatus},
+    },
+    unistd,
+};
+
+use crate::common::{arch, elf};
+
+/// where the restorer's only segment is loaded; aligned for 64K pages
+pub const RESTORER_BASE: u64 = 0x100000;
+
+/// struct clone_args from <linux/sched.h>, up to and including set_tid_size
+#[repr(C)]
+#[derive(Default)]
+pub struct CloneArgs {
+    pub flags: u64,
+    pub pidfd: u64,
+    pub child_tid: u64,
+    pub parent_tid: u64,
+    pub exit_signal: u64,
+    pub stack: u64,
+    pub stack_size: u64,
+    pub tls: u64,
+    /// pointer to an array of set_tid_size pids, innermost pid namespace first
+    pub set_tid: u64,
+    pub set_tid_size: u64,
+}
+
+pub const CLONE_ARGS_SIZE: usize = std::mem::size_of::<CloneArgs>();
+
+impl CloneArgs {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            self.flags,
+            self.pidfd,
+            self.child_tid,
+            self.parent_tid,
+            self.exit_signal,
+            self.stack,
+            self.stack_size,
+            self.tls,
+            self.set_tid,
+            self.set_tid_size,
+        ]
+        .iter()
+        .flat_map(|value| value.to_ne_bytes())
+        .collect()
+    }
+}
+
+/// returns a static ELF executable with a single segment holding `arch::RESTORER_CODE`
+pub fn image() -> Vec<u8> {
+    elf(&arch::RESTORER_CODE)
+}
+
+fn elf(code: &[u8]) -> Vec<u8> {
+    let size = (elf::EHDR_SIZE + elf::PHDR_SIZE + code.len()) as u64;
+    let entry = RESTORER_BASE + (elf::EHDR_SIZE + elf::PHDR_SIZE) as u64;
+
+    let mut r = elf::file_header(elf::ET_EXEC, entry, 1);
+    // the whole file, code and headers alike
+    let header = elf::ProgramHeader {
+        p_type: elf::PT_LOAD,
+        flags: elf::PF_R | elf::PF_X,
+        offset: 0,
+        vaddr: RESTORER_BASE,
+        filesz: size,
+        memsz: size,
+        align: 0x10000,
+    };
+    r.extend_from_slice(&header.to_bytes());
+    r.extend_from_slice(code);
+    r
+}
+
+fn write_to_memfd(bytes: &[u8]) -> Result<OwnedFd> {
+    let name = CString::new("proctool-restorer")?;
+    let fd = sys::memfd::memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC)
+        .map_err(|e| anyhow!("memfd_create failed: {}", e))?;
+    let mut file = File::from(fd);
+    file.write_all(bytes)?;
+    Ok(file.into())
+}
+
+/// starts the restorer as a traced child, stopped right after the exec
+pub fn spawn() -> Result<unistd::Pid> {
+    spawn_with(|| Ok(unsafe { unistd::fork() }?))
+}
+
+/// like `spawn`, but the restorer gets `pid`, which has to be free
+pub fn spawn_with_pid(pid: i32) -> Result<unistd::Pid> {
+    let set_tid = [pid];
+    let args = CloneArgs {
+        exit_signal: libc::SIGCHLD as u64,
+        set_tid: set_tid.as_ptr() as u64,
+        set_tid_size: 1,
+        ..Default::default()
+    };
+
+    spawn_with(|| match unsafe { clone3(&args) } {
+        Err(libc::ENOSYS) => fork_after_ns_last_pid(pid),
+        Err(errno) => Err(set_tid_error(pid, errno)),
+        Ok(r) => Ok(r),
+    })
+}
+
+/// like `spawn`, but the restorer is pid 1 of a new pid namespace, so that it can fork processes
+/// with any pid; see `arch::REAPER_CODE`
+pub fn spawn_in_new_pid_namespace() -> Result<unistd::Pid> {
+    let args = CloneArgs {
+        flags: libc::CLONE_NEWPID as u64,
+        exit_signal: libc::SIGCHLD as u64,
+        ..Default::default()
+    };
+
+    spawn_with(|| match unsafe { clone3(&args) } {
+        Err(libc::EPERM) => Err(anyhow!(
+            "not allowed to create a pid namespace: needs CAP_SYS_ADMIN, e.g. running as root"
+        )),
+        Err(errno) => Err(anyhow!(
+            "clone3 failed: {}",
+            std::io::Error::from_raw_os_error(errno)
+        )),
+        Ok(r) => Ok(r),
+    })
+}
+
+/// explains why clone3() couldn't create `pid`
+pub fn set_tid_error(pid: i32, errno: i32) -> anyhow::Error {
+    match errno {
+        libc::EEXIST => anyhow!(
+            "pid {} is already in use; thawing into a new pid namespace avoids that",
+            pid
+        ),
+        libc::EPERM => anyhow!(
+            "not allowed to choose pid {}: needs CAP_CHECKPOINT_RESTORE or CAP_SYS_ADMIN",
+            pid
+        ),
+        libc::EINVAL => anyhow!(
+            "pid {} is out of range here (see /proc/sys/kernel/pid_max)",
+            pid
+        ),
+        _ => anyhow!(
+            "clone3 with pid {} failed: {}",
+            pid,
+            std::io::Error::from_raw_os_error(errno)
+        ),
+    }
+}
+
+/// makes `pid` the next one handed out in our pid namespace, unless someone else forks first;
+/// the fallback for kernels without clone3() (before 5.5)
+pub fn set_ns_last_pid(pid: i32) -> Result<()> {
+    fs::write("/proc/sys/kernel/ns_last_pid", format!("{}", pid - 1))
+        .map_err(|e| anyhow!("could not write ns_last_pid to get pid {}: {}", pid, e))
+}
+
+/// whether process `pid` is in our pid namespace, where ids mean the same to it as to us and
+/// `set_ns_last_pid` applies to it
+pub fn shares_pid_namespace(pid: i32) -> Result<bool> {
+    let ns = |path: &str| {
+        fs::metadata(path)
+            .map(|m| (m.dev(), m.ino()))
+            .map_err(|e| anyhow!("could not read {}: {}", path, e))
+    };
+    Ok(ns("/proc/self/ns/pid")? == ns(&format!("/proc/{}/ns/pid", pid))?)
+}
+
+fn fork_after_ns_last_pid(pid: i32) -> Result<unistd::ForkResult> {
+    set_ns_last_pid(pid)?;
+    let r = unsafe { unistd::fork() }?;
+    if let unistd::ForkResult::Parent { child } = r {
+        if child.as_raw() != pid {
+            let _ = sys::signal::kill(child, Signal::SIGKILL);
+            let _ = sys::wait::waitpid(child, None);
+            return Err(anyhow!(
+                "got pid {} instead of {}: something else forked in between",
+                child,
+                pid
+            ));
+        }
+    }
+    Ok(r)
+}
+
+/// fork() with extras; returns the errno on failure
+unsafe fn clone3(args: &CloneArgs) -> std::result::Result<unistd::ForkResult, i32> {
+    let r = libc::syscall(libc::SYS_clone3, args as *const CloneArgs, CLONE_ARGS_SIZE);
+    match r {
+        r if r < 0 => Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(0)),
+        0 => Ok(unistd::ForkResult::Child),
+        child => Ok(unistd::ForkResult::Parent {
+            child: unistd::Pid::from_raw(child as i32),
+        }),
+    }
+}
+
+fn spawn_with<F: FnOnce() -> Result<unistd::ForkResult>>(fork: F) -> Result<unistd::Pid> {
+    // everything the child needs is prepared before the fork, so that it only makes syscalls
+    let fd = write_to_memfd(&image())?;
+    let arg0 = CString::new("proctool-restorer")?;
+    let argv = [arg0.as_ptr(), std::ptr::null()];
+    let envp: [*const libc::c_char; 1] = [std::ptr::null()];
+
+    match fork()? {
+        unistd::ForkResult::Parent { child } => {
+            drop(fd);
+            // PTRACE_TRACEME turns the exec into a SIGTRAP stop
+            match sys::wait::waitpid(child, Some(WaitPidFlag::WSTOPPED)) {
+                Ok(WaitStatus::Stopped(_, Signal::SIGTRAP)) => Ok(child),
+                Ok(status) => Err(anyhow!("restorer did not start: {:?}", status)),
+                Err(e) => Err(anyhow!("failed to waitpid: {}", e)),
+            }
+        }
+        unistd::ForkResult::Child => {
+            let _ = sys::ptrace::traceme();
+            // not `unistd::fexecve`, which allocates the pointer arrays
+            unsafe {
+                libc::fexecve(fd.as_raw_fd(), argv.as_ptr(), envp.as_ptr());
+                libc::_exit(127)
+            }
+        }
+    }
+}
+
+/// returns the lowest address from `RESTORER_BASE` up where `size` bytes don't overlap any of
+/// the `taken` (start, end) ranges
+pub fn find_free_address(taken: &[(u64, u64)], size: u64) -> u64 {
+    let mut taken = taken.to_vec();
+    taken.sort();
+
+    let mut addr = RESTORER_BASE;
+    for (start, end) in taken {
+        if addr + size <= start {
+            break;
+        }
+        addr = addr.max(end);
+    }
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_runs() {
+        // without a tracer the restorer just exits with 1
+        let fd = write_to_memfd(&image()).unwrap();
+        let path = format!("/proc/self/fd/{}", fd.as_raw_fd());
+        let status = std::process::Command::new(path).status().unwrap();
+        assert_eq!(status.code(), Some(1));
+    }
+
+    #[test]
+    fn test_reaper_exits_without_children() {
+        let fd = write_to_memfd(&elf(&arch::REAPER_CODE)).unwrap();
+        let path = format!("/proc/self/fd/{}", fd.as_raw_fd());
+        let status = std::process::Command::new(path).status().unwrap();
+        assert_eq!(status.code(), Some(0));
+    }
+
+    #[test]
+    fn test_clone_args_size() {
+        // CLONE_ARGS_SIZE_VER1
+        assert_eq!(CLONE_ARGS_SIZE, 80);
+        assert_eq!(CloneArgs::default().to_bytes().len(), CLONE_ARGS_SIZE);
+    }
+
+    #[test]
+    fn test_shares_pid_namespace() {
+        assert!(shares_pid_namespace(std::process::id() as i32).unwrap());
+        assert!(shares_pid_namespace(-1).is_err());
+    }
+
+    #[test]
+    fn test_find_free_address() {
+        let base = RESTORER_BASE;
+        assert_eq!(find_free_address(&[], 0x1000), base);
+        assert_eq!(
+            find_free_address(
+                &[(base + 0x3000, base + 0x4000), (base, base + 0x1000)],
+                0x1000
+            ),
+            base + 0x1000
+        );
+        assert_eq!(
+            find_free_address(
+                &[(base, base + 0x1000), (base + 0x1800, base + 0x4000)],
+                0x1000
+            ),
+            base + 0x4000
+        );
+        assert_eq!(
+            find_free_address(&[(0, base + 0x2000)], 0x1000),
+            base + 0x2000
+        );
+    }
+}
@@ -0,0 +1,196 @@
+// Signal state that has to survive a freeze/thaw or a telefork: the handlers a process installed
+// and the signals queued for it that haven't been delivered yet. Blocked masks are per-thread and
+// live with the registers instead.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// size of the kernel's `struct sigaction` as taken by rt_sigaction(2), which is the same on
+/// every supported architecture: handler, flags, restorer and a 64-bit mask
+pub const SIGACTION_SIZE: usize = 32;
+
+/// size of `siginfo_t`
+pub const SIGINFO_SIZE: usize = 128;
+
+/// the size of `sigset_t` as far as the kernel is concerned, passed to every rt_sig* syscall
+pub const SIGSET_SIZE: u64 = 8;
+
+/// `si_code` of a signal sent with kill(2) and the like
+pub const SI_USER: i32 = 0;
+
+/// `si_code` of a signal the kernel sent
+pub const SI_KERNEL: i32 = 0x80;
+
+/// a mask blocking every signal; the kernel leaves SIGKILL and SIGSTOP out by itself
+pub const ALL_SIGNALS: u64 = !0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SignalState {
+    /// only signals whose action isn't the default
+    pub actions: Vec<SignalAction>,
+    /// queued for the process as a whole, as opposed to a particular thread
+    pub pending: Vec<QueuedSignal>,
+}
+
+impl SignalState {
+    pub fn check(&self) -> Result<()> {
+        self.pending.iter().try_for_each(|signal| signal.check())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SignalAction {
+    pub signal: i32,
+    /// SIG_DFL (0), SIG_IGN (1) or the address of the handler
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: u64,
+}
+
+impl SignalAction {
+    pub fn from_bytes(signal: i32, bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != SIGACTION_SIZE {
+            return Err(anyhow!(
+                "expected {} bytes for struct sigaction but got {}",
+                SIGACTION_SIZE,
+                bytes.len()
+            ));
+        }
+
+        let word = |i: usize| u64::from_le_bytes(bytes[i * 8..(i + 1) * 8].try_into().unwrap());
+        Ok(Self {
+            signal,
+            handler: word(0),
+            flags: word(1),
+            restorer: word(2),
+            mask: word(3),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.handler, self.flags, self.restorer, self.mask]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.handler == libc::SIG_DFL as u64 && self.flags == 0 && self.mask == 0
+    }
+}
+
+/// a signal that was raised but not yet delivered, kept as the raw `siginfo_t` so that it can be
+/// queued again with rt_sigqueueinfo(2) without losing the sender or value
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueuedSignal {
+    pub siginfo: Vec<u8>,
+}
+
+impl QueuedSignal {
+    /// rejects a `siginfo_t` of the wrong size, which the accessors below would panic on; anything
+    /// read from an image or a telefork request has to pass this first
+    pub fn check(&self) -> Result<()> {
+        if self.siginfo.len() != SIGINFO_SIZE {
+            return Err(anyhow!(
+                "expected {} bytes for siginfo_t but got {}",
+                SIGINFO_SIZE,
+                self.siginfo.len()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn signal(&self) -> i32 {
+        self.field(0)
+    }
+
+    /// `si_code`
+    pub fn code(&self) -> i32 {
+        self.field(8)
+    }
+
+    /// `si_pid`, which only means something for signals sent by a process, e.g. with kill(2)
+    pub fn sender(&self) -> i32 {
+        // the union after si_signo, si_errno and si_code is 8-byte aligned
+        self.field(16)
+    }
+
+    fn field(&self, offset: usize) -> i32 {
+        i32::from_le_bytes(self.siginfo[offset..offset + 4].try_into().unwrap())
+    }
+}
+
+// _NSIG in the kernel, which counts from 1
+const NSIG: i32 = 64;
+
+/// the signals whose actions can be read and changed: everything but SIGKILL and SIGSTOP
+pub fn catchable_signals() -> impl Iterator<Item = i32> {
+    (1..=NSIG).filter(|sig| *sig != libc::SIGKILL && *sig != libc::SIGSTOP)
+}
+
+/// an address below the stack pointer that a stopped thread won't miss, for the odd syscall
+/// argument that has to live in its memory
+///
+/// leaves room for the 128-byte red zone on x86-64 and keeps 16-byte alignment
+pub fn stack_scratch_address(sp: u64) -> u64 {
+    (sp - 512) & !0xf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signal_action_round_trip() {
+        let action = SignalAction {
+            signal: libc::SIGINT,
+            handler: 0x5555_5555_1234,
+            flags: libc::SA_RESTART as u64,
+            restorer: 0x7fff_0000_1000,
+            mask: 1 << (libc::SIGTERM - 1),
+        };
+        let bytes = action.to_bytes();
+        assert_eq!(bytes.len(), SIGACTION_SIZE);
+        assert_eq!(
+            SignalAction::from_bytes(libc::SIGINT, &bytes).unwrap(),
+            action
+        );
+        assert!(!action.is_default());
+
+        assert!(SignalAction::from_bytes(libc::SIGINT, &bytes[1..]).is_err());
+        assert!(SignalAction::from_bytes(libc::SIGINT, &[0; SIGACTION_SIZE])
+            .unwrap()
+            .is_default());
+    }
+
+    #[test]
+    fn test_queued_signal_check() {
+        let mut siginfo = vec![0u8; SIGINFO_SIZE];
+        siginfo[..4].copy_from_slice(&libc::SIGUSR1.to_le_bytes());
+        siginfo[16..20].copy_from_slice(&1234i32.to_le_bytes());
+        let signal = QueuedSignal { siginfo };
+        assert!(signal.check().is_ok());
+        assert_eq!(signal.signal(), libc::SIGUSR1);
+        assert_eq!(signal.sender(), 1234);
+
+        let state = SignalState {
+            actions: Vec::new(),
+            pending: vec![
+                signal,
+                QueuedSignal {
+                    siginfo: vec![0; 3],
+                },
+            ],
+        };
+        assert!(state.check().is_err());
+    }
+
+    #[test]
+    fn test_catchable_signals() {
+        let signals: Vec<i32> = catchable_signals().collect();
+        assert!(signals.contains(&libc::SIGINT));
+        assert!(!signals.contains(&libc::SIGKILL));
+        assert!(!signals.contains(&libc::SIGSTOP));
+    }
+}
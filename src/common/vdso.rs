@@ -0,0 +1,96 @@
+// A restored process has to use the vDSO of the kernel it runs on: the saved one reads the time
+// out of [vvar] pages laid out for the kernel it was saved on. If the kernel's vDSO exports the
+// same functions at the same offsets, it can simply be moved to where the old one was; otherwise
+// the old one is put back with each of its functions redirected to the kernel's.
+
+use anyhow::Result;
+
+use crate::{
+    common::{arch, elf},
+    teleclient::myprocfs::MemoryMap,
+};
+
+/// returns the saved contents of a [vdso] region
+pub fn saved_image(memory_map: &MemoryMap) -> Vec<u8> {
+    let mut r = vec![0; memory_map.size as usize];
+    for (addr, bytes) in memory_map.populated_runs() {
+        let start = (addr - memory_map.base_address) as usize;
+        r[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+    r
+}
+
+/// whether code that called into `old` works unchanged with `new` mapped in its place
+pub fn lines_up(old: &[u8], new: &[u8]) -> Result<bool> {
+    Ok(old.len() == new.len() && elf::function_symbols(old)? == elf::function_symbols(new)?)
+}
+
+/// overwrites the start of every function in `old` with a jump to the function of the same name
+/// in `new`, which is mapped at `new_base`; returns the names of those that couldn't be redirected
+pub fn redirect(old: &mut [u8], new: &[u8], new_base: u64) -> Result<Vec<String>> {
+    let new_symbols = elf::function_symbols(new)?;
+    let old_symbols = elf::function_symbols(old)?;
+    let mut starts: Vec<u64> = old_symbols.iter().map(|s| s.offset).collect();
+    starts.sort();
+
+    let mut missing = Vec::new();
+    for symbol in old_symbols {
+        // small functions are padded up to the next one, which is just as good
+        let next = starts.iter().find(|start| **start > symbol.offset);
+        let room = next.map_or(symbol.size, |next| symbol.size.max(next - symbol.offset));
+        let target = new_symbols.iter().find(|s| s.name == symbol.name);
+        let start = symbol.offset as usize;
+        match target.map(|target| arch::jump_to(new_base + target.offset)) {
+            Some(jump) if jump.len() as u64 <= room && start + jump.len() <= old.len() => {
+                old[start..start + jump.len()].copy_from_slice(&jump);
+            }
+            _ => missing.push(symbol.name),
+        }
+    }
+    Ok(missing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::teleclient::myprocfs;
+
+    // our own vDSO
+    fn vdso() -> (u64, Vec<u8>) {
+        let maps = myprocfs::read_memory_maps(std::process::id() as i32).unwrap();
+        let map = maps.iter().find(|map| map.label == "[vdso]").unwrap();
+        let p = map.base_address as *const u8;
+        let image = unsafe { std::slice::from_raw_parts(p, map.size as usize) };
+        (map.base_address, image.to_vec())
+    }
+
+    #[test]
+    fn test_symbols() {
+        let (_, image) = vdso();
+        let symbols = elf::function_symbols(&image).unwrap();
+        assert!(symbols.iter().any(|s| s.name.contains("clock_gettime")));
+        assert!(symbols.iter().all(|s| s.offset < image.len() as u64));
+        assert!(lines_up(&image, &image).unwrap());
+        assert!(!lines_up(&image, &image[..image.len() - 1]).unwrap());
+
+        // a section whose end doesn't fit in 64 bits
+        let mut bad = image.clone();
+        let shoff = u64::from_le_bytes(bad[40..48].try_into().unwrap()) as usize;
+        bad[shoff + 24..shoff + 32].copy_from_slice(&1u64.to_le_bytes());
+        bad[shoff + 32..shoff + 40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(elf::function_symbols(&bad).is_err());
+    }
+
+    #[test]
+    fn test_redirect() {
+        let (base, image) = vdso();
+        let mut old = image.clone();
+        assert!(redirect(&mut old, &image, base).unwrap().is_empty());
+
+        let symbol = elf::function_symbols(&image).unwrap().remove(0);
+        let jump = arch::jump_to(base + symbol.offset);
+        let start = symbol.offset as usize;
+        assert_eq!(&old[start..start + jump.len()], &jump[..]);
+        assert!(elf::function_symbols(b"#!/bin/sh\n").is_err());
+    }
+}
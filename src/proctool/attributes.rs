@@ -0,0 +1,254 @@
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// process-wide state that lives in the kernel rather than in memory
+///
+/// the environment isn't here: it's on the stack and comes back with the memory maps
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProcessAttributes {
+    pub cwd: String,
+    pub umask: u32,
+    /// the name shown by ps, at most 15 bytes
+    pub comm: String,
+    pub rlimits: Vec<ResourceLimit>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ResourceLimit {
+    /// RLIMIT_*
+    pub resource: u32,
+    pub soft: u64,
+    pub hard: u64,
+}
+
+/// where the kernel thinks the code, heap, stack, arguments and environment are; restored with
+/// prctl(PR_SET_MM_MAP) since otherwise they describe the process that did the thaw
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MemoryLayout {
+    pub start_code: u64,
+    pub end_code: u64,
+    pub start_data: u64,
+    pub end_data: u64,
+    pub start_brk: u64,
+    /// not in /proc/<pid>/stat, so it's read with an injected brk(0)
+    pub brk: u64,
+    pub start_stack: u64,
+    pub arg_start: u64,
+    pub arg_end: u64,
+    pub env_start: u64,
+    pub env_end: u64,
+    /// contents of /proc/<pid>/auxv
+    pub auxv: Vec<u8>,
+}
+
+// sizeof(struct prctl_mm_map)
+pub const PRCTL_MM_MAP_SIZE: usize = 104;
+
+impl MemoryLayout {
+    /// returns a struct prctl_mm_map, pointing at a copy of `auxv` at `auxv_addr`
+    pub fn to_prctl_mm_map(&self, auxv_addr: u64) -> Vec<u8> {
+        let mut r = Vec::with_capacity(PRCTL_MM_MAP_SIZE);
+        for value in [
+            self.start_code,
+            self.end_code,
+            self.start_data,
+            self.end_data,
+            self.start_brk,
+            self.brk,
+            self.start_stack,
+            self.arg_start,
+            self.arg_end,
+            self.env_start,
+            self.env_end,
+            auxv_addr,
+        ] {
+            r.extend_from_slice(&value.to_ne_bytes());
+        }
+        r.extend_from_slice(&(self.auxv.len() as u32).to_ne_bytes());
+        // exe_fd: -1 leaves /proc/<pid>/exe alone
+        r.extend_from_slice(&(-1i32).to_ne_bytes());
+        r
+    }
+}
+
+pub fn read_memory_layout(pid: i32, brk: u64) -> Result<MemoryLayout> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    let auxv = fs::read(format!("/proc/{}/auxv", pid))
+        .map_err(|e| anyhow!("could not read auxv of {}: {}", pid, e))?;
+
+    let mut layout = parse_stat_layout(&stat)?;
+    layout.brk = brk;
+    layout.auxv = auxv;
+    Ok(layout)
+}
+
+fn parse_stat_layout(stat: &str) -> Result<MemoryLayout> {
+    // the name in parentheses can contain anything, so count fields from the last ')'
+    let rest = stat
+        .rsplit_once(')')
+        .ok_or_else(|| anyhow!("malformed stat: {}", stat))?
+        .1;
+    let fields: Vec<&str> = rest.split_ascii_whitespace().collect();
+    // numbered as in proc(5); `fields` starts at field 3
+    let field = |n: usize| -> Result<u64> {
+        let value = fields
+            .get(n - 3)
+            .ok_or_else(|| anyhow!("stat has no field {}", n))?;
+        value
+            .parse()
+            .map_err(|e| anyhow!("bad stat field {} ({}): {}", n, value, e))
+    };
+
+    Ok(MemoryLayout {
+        start_code: field(26)?,
+        end_code: field(27)?,
+        start_stack: field(28)?,
+        start_data: field(45)?,
+        end_data: field(46)?,
+        start_brk: field(47)?,
+        brk: 0,
+        arg_start: field(48)?,
+        arg_end: field(49)?,
+        env_start: field(50)?,
+        env_end: field(51)?,
+        auxv: Vec::new(),
+    })
+}
+
+pub fn read_attributes(pid: i32) -> Result<ProcessAttributes> {
+    let cwd = fs::read_link(format!("/proc/{}/cwd", pid))
+        .map_err(|e| anyhow!("could not read cwd of {}: {}", pid, e))?;
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid))?;
+    let limits = fs::read_to_string(format!("/proc/{}/limits", pid))?;
+
+    Ok(ProcessAttributes {
+        cwd: cwd.to_string_lossy().to_string(),
+        umask: parse_umask(&status)?,
+        comm: comm.trim_end_matches('\n').to_string(),
+        rlimits: parse_limits(&limits)?,
+    })
+}
+
+fn parse_umask(status: &str) -> Result<u32> {
+    let value = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Umask:"))
+        .ok_or_else(|| anyhow!("no Umask line in status"))?;
+    u32::from_str_radix(value.trim(), 8).map_err(|e| anyhow!("bad umask {}: {}", value, e))
+}
+
+/// parses /proc/<pid>/limits, whose rows come in RLIMIT_* order after a header
+fn parse_limits(contents: &str) -> Result<Vec<ResourceLimit>> {
+    // columns are fixed width: "%-25s %-20s %-20s %-10s"
+    let column = |line: &str, start: usize, end: usize| -> Result<u64> {
+        let value = line
+            .get(start..end.min(line.len()))
+            .ok_or_else(|| anyhow!("short line in limits: {}", line))?
+            .trim();
+        if value == "unlimited" {
+            return Ok(libc::RLIM_INFINITY);
+        }
+        value
+            .parse()
+            .map_err(|e| anyhow!("bad limit {}: {}", value, e))
+    };
+
+    contents
+        .lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(resource, line)| {
+            Ok(ResourceLimit {
+                resource: resource as u32,
+                soft: column(line, 26, 46)?,
+                hard: column(line, 47, 67)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_limits() {
+        let contents = "\
+Limit                     Soft Limit           Hard Limit           Units
+Max cpu time              unlimited            unlimited            seconds
+Max file size             unlimited            unlimited            bytes
+Max data size             unlimited            unlimited            bytes
+Max stack size            8388608              unlimited            bytes
+Max core file size        0                    unlimited            bytes
+Max resident set          unlimited            unlimited            bytes
+Max processes             62811                62811                processes
+Max open files            1024                 524288               files
+Max locked memory         8388608              8388608              bytes
+Max address space         unlimited            unlimited            bytes
+Max file locks            unlimited            unlimited            locks
+Max pending signals       62811                62811                signals
+Max msgqueue size         819200               819200               bytes
+Max nice priority         0                    0
+Max realtime priority     0                    0
+Max realtime timeout      unlimited            unlimited            us
+";
+        let limits = parse_limits(contents).unwrap();
+        assert_eq!(limits.len(), 16);
+        assert_eq!(limits[libc::RLIMIT_STACK as usize].soft, 8388608);
+        assert_eq!(
+            limits[libc::RLIMIT_STACK as usize].hard,
+            libc::RLIM_INFINITY
+        );
+        assert_eq!(limits[libc::RLIMIT_NOFILE as usize].soft, 1024);
+        assert_eq!(limits[libc::RLIMIT_NOFILE as usize].hard, 524288);
+        assert_eq!(
+            limits[libc::RLIMIT_RTTIME as usize].soft,
+            libc::RLIM_INFINITY
+        );
+    }
+
+    #[test]
+    fn test_parse_stat_layout() {
+        let stat = "1234 (a (weird) name) S 1 1234 1234 0 -1 4194560 100 0 0 0 0 0 0 0 20 0 1 0 \
+                    100 1000 200 18446744073709551615 4194304 4198400 140733000000000 0 0 0 0 \
+                    0 0 0 0 0 17 3 0 0 0 0 0 6295552 6296000 6299648 140733000001000 \
+                    140733000001100 140733000001100 140733000002000 0\n";
+        let layout = parse_stat_layout(stat).unwrap();
+        assert_eq!(layout.start_code, 4194304);
+        assert_eq!(layout.end_code, 4198400);
+        assert_eq!(layout.start_stack, 140733000000000);
+        assert_eq!(layout.start_data, 6295552);
+        assert_eq!(layout.end_data, 6296000);
+        assert_eq!(layout.start_brk, 6299648);
+        assert_eq!(layout.arg_start, 140733000001000);
+        assert_eq!(layout.env_end, 140733000002000);
+    }
+
+    #[test]
+    fn test_prctl_mm_map_size() {
+        let layout = parse_stat_layout(&fs::read_to_string("/proc/self/stat").unwrap()).unwrap();
+        assert_eq!(layout.to_prctl_mm_map(0).len(), PRCTL_MM_MAP_SIZE);
+    }
+
+    #[test]
+    fn test_parse_umask() {
+        assert_eq!(
+            parse_umask("Name:\tcat\nUmask:\t0027\nState:\tR\n").unwrap(),
+            0o027
+        );
+    }
+
+    #[test]
+    fn test_read_own_attributes() {
+        let attributes = read_attributes(std::process::id() as i32).unwrap();
+        assert_eq!(
+            attributes.cwd,
+            std::env::current_dir().unwrap().to_string_lossy()
+        );
+        assert!(!attributes.rlimits.is_empty());
+    }
+}
@@ -1,7 +1,6 @@
-use std::io::BufReader;
+use std::io::Write;
 use std::net::TcpStream;
 use std::process::Command;
-use std::{fs, io::Write};
 
 use anyhow::{anyhow, Result};
 
@@ -10,8 +9,10 @@ use clap::Parser;
 use nix::{sys, unistd};
 use process_magic::proctool::pcontroller::{self, ProcessController};
 use process_magic::proctool::{
-    common::{Args, DaemonMessage, PORT},
-    cryogenics, procinfo, terminals,
+    common::{Args, CheckpointsCommand, DaemonMessage, StateCommand, PORT},
+    coredump, cryogenics, image, inspect, procinfo,
+    store::{self, CheckpointInfo, Store},
+    terminals,
 };
 
 fn main() -> Result<()> {
@@ -57,24 +58,160 @@ fn main() -> Result<()> {
             print_what_terminal()?;
         }
         Args::Freeze(args) => {
+            let pids = if args.tree {
+                procinfo::get_process_tree(args.pid)?
+            } else if args.pgid {
+                procinfo::get_process_group(args.pid)?
+            } else {
+                vec![args.pid]
+            };
+            let pids: Vec<unistd::Pid> = pids.into_iter().map(unistd::Pid::from_raw).collect();
+            let store = Store::from_env(&root)?;
+            let name = args
+                .name
+                .unwrap_or_else(|| store.unique_name(&args.pid.to_string()));
+            // before anything is stopped
+            store.check_name(&name)?;
+            let info = CheckpointInfo::describe(&name, args.pid)?;
+            let checkpoint = if args.keep_running {
+                cryogenics::freeze_all_running(&pids)?
+            } else {
+                cryogenics::freeze_all(&pids)?
+            };
+
+            let format = if args.json {
+                image::Format::Json
+            } else if args.compress {
+                image::Format::Binary(image::Compression::Lz4)
+            } else {
+                image::Format::Binary(image::Compression::None)
+            };
+            let info = store.save(info, checkpoint, format)?;
+            // children first, so that none of them see their parent go away
+            for pid in pids.iter().rev() {
+                if args.kill {
+                    sys::signal::kill(*pid, sys::signal::SIGKILL)
+                        .map_err(|e| anyhow!("could not kill process {}: {}", pid, e))?;
+                } else if args.takeover {
+                    pcontroller::takeover(*pid, &format!("{}/bin/risen", root), false)?;
+                }
+            }
+            println!("Saved as {} ({})", info.name, store.image_path(&info.name));
+        }
+        Args::Checkpoint(args) => {
             let pid = unistd::Pid::from_raw(args.pid);
-            let state = cryogenics::freeze(pid)?;
+            let store = Store::from_env(&root)?;
+            let previous = store.latest_live(args.pid)?;
+            let parent = if args.incremental {
+                let previous = previous.as_ref().ok_or(anyhow!(
+                    "no previous checkpoint of {} to build on",
+                    args.pid
+                ))?;
+                let checkpoint = image::load(&store.image_path(&previous.name))?;
+                Some((
+                    previous.name.clone(),
+                    Store::file_name(&previous.name),
+                    checkpoint,
+                ))
+            } else {
+                None
+            };
 
-            // TODO: don't save file as root
-            // This doesn't work:
-            //   unistd::setuid(unistd::getuid())?;
-            let fname = format!("{}.state", args.pid);
-            let mut f = fs::File::options().write(true).create(true).open(&fname)?;
+            let name = args.name.unwrap_or_else(|| {
+                let n = store
+                    .list()
+                    .map(|infos| infos.iter().filter(|i| i.pid == args.pid && i.live).count())
+                    .unwrap_or(0);
+                store.unique_name(&format!("{}.{}", args.pid, n))
+            });
+            store.check_name(&name)?;
+            let mut info = CheckpointInfo::describe(&name, args.pid)?;
+            info.live = true;
+            info.parent = parent.as_ref().map(|(name, _, _)| name.clone());
+
+            let checkpoint = cryogenics::checkpoint(
+                pid,
+                parent
+                    .as_ref()
+                    .map(|(_, fname, parent)| (fname.as_str(), parent)),
+            )?;
+            let compression = if args.compress {
+                image::Compression::Lz4
+            } else {
+                image::Compression::None
+            };
+            let info = store.save(info, checkpoint, image::Format::Binary(compression))?;
+            println!("Saved as {} ({})", info.name, store.image_path(&info.name));
+        }
+        Args::Checkpoints(args) => {
+            let store = Store::from_env(&root)?;
+            match args.command {
+                CheckpointsCommand::List => print!("{}", store::format_list(&store.list()?)),
+                CheckpointsCommand::Rm(args) => {
+                    for name in args.names.iter() {
+                        store.remove(name)?;
+                        println!("Removed {}", name);
+                    }
+                }
+                CheckpointsCommand::Prune(args) => {
+                    for name in store.prune(args.keep)? {
+                        println!("Removed {}", name);
+                    }
+                }
+            }
+        }
+        Args::State(args) => match args.command {
+            StateCommand::Show(args) => {
+                let checkpoint = image::load(&args.path)?;
+                print!("{}", inspect::show(&checkpoint));
+            }
+            StateCommand::Diff(args) => {
+                let a = image::load_chain(&args.a)?;
+                let b = image::load_chain(&args.b)?;
+                let diffs = inspect::diff(&a, &b)?;
+                print!("{}", inspect::show_diff(&diffs));
+            }
+            StateCommand::ToCore(args) => {
+                let checkpoint = image::load_chain(&args.path)?;
+                if args.output.is_some() && checkpoint.processes.len() > 1 {
+                    return Err(anyhow!(
+                        "{} holds {} processes, so --output can't be used",
+                        args.path,
+                        checkpoint.processes.len()
+                    ));
+                }
+                for state in checkpoint.processes.iter() {
+                    let fname = args.output.clone().unwrap_or(format!("{}.core", state.pid));
+                    coredump::save(&fname, state, checkpoint.page_size)?;
+                    println!("Saved to {}", fname);
+                }
+            }
+        },
+        Args::Gcore(args) => {
+            let pid = unistd::Pid::from_raw(args.pid);
+            let checkpoint = cryogenics::freeze_all_running(&[pid])?;
 
-            serde_json::to_writer(&mut f, &state)?;
-            pcontroller::takeover(pid, &format!("{}/bin/risen", root), false)?;
+            let fname = args.output.unwrap_or(format!("{}.core", args.pid));
+            coredump::save(&fname, &checkpoint.processes[0], checkpoint.page_size)?;
             println!("Saved to {}", fname);
         }
         Args::Thaw(args) => {
-            let f = fs::File::open(&args.path)?;
-            let mut reader = BufReader::new(f);
-            let state: cryogenics::ProcessState = serde_json::from_reader(&mut reader)?;
-            cryogenics::thaw(&state)?;
+            let path = match args.name {
+                Some(name) => {
+                    let store = Store::from_env(&root)?;
+                    store.get(&name)?;
+                    store.image_path(&name)
+                }
+                None => args.path.ok_or(anyhow!("no image to thaw"))?,
+            };
+            let checkpoint = image::load_chain(&path)?;
+            let options = cryogenics::ThawOptions {
+                same_pid: args.same_pid,
+                new_pid_namespace: args.new_pid_namespace,
+                lazy: args.lazy,
+                check: args.check,
+            };
+            cryogenics::thaw(&checkpoint, &options)?;
         }
         Args::UnmapChild => match unsafe { unistd::fork() }? {
             unistd::ForkResult::Parent { child } => {
@@ -98,7 +235,9 @@ fn main() -> Result<()> {
         },
         Args::Oblivion(_) => {
             dispatch_to_daemon(args)?;
-            Command::new(format!("{}/bin/oblivion", root)).arg("3").spawn()?;
+            Command::new(format!("{}/bin/oblivion", root))
+                .arg("3")
+                .spawn()?;
         }
         _ => {
             dispatch_to_daemon(args)?;
@@ -1,7 +1,9 @@
 use std::{
+    collections::VecDeque,
     io::{BufRead, BufReader},
     net::{TcpListener, TcpStream},
     os::fd::RawFd,
+    time::Duration,
 };
 
 use anyhow::{anyhow, Result};
@@ -15,9 +17,11 @@ use log4rs::{
 use nix::{fcntl, sys, unistd};
 use process_magic::{
     proctool::{
-        common::{Args, DaemonMessage, PORT},
+        common::{Args, AutocheckpointArgs, DaemonMessage, PORT},
+        cryogenics, image,
         pcontroller::{self, ProcessController},
         procinfo,
+        store::{CheckpointInfo, Store},
         terminals::{self, write_to_stdin},
     },
     teleclient::myprocfs,
@@ -127,6 +131,15 @@ fn run_command(root: &str, args: Args) -> Result<()> {
             //     biggest_terminal_size
             // );
         }
+        Args::Autocheckpoint(args) => {
+            // runs for as long as the process does, so it can't hold up the next client
+            let root = root.to_string();
+            std::thread::spawn(move || {
+                if let Err(e) = autocheckpoint(&root, &args) {
+                    log::error!("autocheckpoint of {} stopped: {}", args.pid, e);
+                }
+            });
+        }
         Args::Pause(args) => {
             let pid = unistd::Pid::from_raw(args.pid);
             let mut controller = ProcessController::new(pid);
@@ -249,9 +262,11 @@ fn run_command(root: &str, args: Args) -> Result<()> {
                     controller.colorize_stderr(region_addr, addr, count)?;
                     controller.continue_syscall()?;
                     let mut new_registers = controller.get_registers()?;
-                    new_registers.regs[0] = original_registers.regs[2];
-                    new_registers.regs[1] = original_registers.regs[1];
-                    new_registers.regs[2] = original_registers.regs[2];
+                    let original_buf = original_registers.arg(1);
+                    let original_count = original_registers.arg(2);
+                    new_registers.set_return_value(original_count);
+                    new_registers.set_arg(1, original_buf);
+                    new_registers.set_arg(2, original_count);
                     controller.set_registers(new_registers)?;
                 } else {
                     controller.continue_syscall()?;
@@ -298,6 +313,74 @@ fn run_command(root: &str, args: Args) -> Result<()> {
     Ok(())
 }
 
+/// freezes `args.pid` every `args.every` seconds and lets it carry on, keeping the `args.keep`
+/// most recent images in the store, until the process exits
+fn autocheckpoint(root: &str, args: &AutocheckpointArgs) -> Result<()> {
+    let pid = unistd::Pid::from_raw(args.pid);
+    let store = Store::from_env(root)?;
+    // tells the process apart from a later one that gets its pid
+    let started = start_time(args.pid).ok_or(anyhow!("process {} isn't running", pid))?;
+    log::info!(
+        "checkpointing {} every {}s, keeping {}",
+        pid,
+        args.every,
+        args.keep
+    );
+
+    let mut saved = VecDeque::new();
+    for n in 0.. {
+        if start_time(args.pid) != Some(started) {
+            break;
+        }
+        let name = store.unique_name(&format!("{}.auto.{}", args.pid, n));
+        match save_checkpoint(&store, pid, &name, args.compress) {
+            Ok(()) => {
+                log::info!("saved {} as {}", pid, name);
+                saved.push_back(name);
+            }
+            // most likely the process exited in the middle of it, which the next round notices
+            Err(e) => log::error!("failed to checkpoint {}: {}", pid, e),
+        }
+        while saved.len() > args.keep {
+            let name = saved.pop_front().unwrap();
+            if let Err(e) = store.remove(&name) {
+                log::error!("failed to remove {}: {}", name, e);
+            }
+        }
+        std::thread::sleep(Duration::from_secs(args.every));
+    }
+
+    match saved.back() {
+        Some(name) => log::info!(
+            "process {} is gone; `proctool thaw --name {}` brings it back",
+            pid,
+            name
+        ),
+        None => log::info!("process {} is gone, and was never checkpointed", pid),
+    }
+    Ok(())
+}
+
+/// like `proctool freeze --keep-running`
+fn save_checkpoint(store: &Store, pid: unistd::Pid, name: &str, compress: bool) -> Result<()> {
+    let info = CheckpointInfo::describe(name, pid.as_raw())?;
+    let checkpoint = cryogenics::freeze_all_running(&[pid])?;
+
+    let compression = if compress {
+        image::Compression::Lz4
+    } else {
+        image::Compression::None
+    };
+    store.save(info, checkpoint, image::Format::Binary(compression))?;
+    Ok(())
+}
+
+/// returns when `pid` was started, or None if there is no such process or it has exited
+fn start_time(pid: i32) -> Option<u64> {
+    let stat = procfs::process::Process::new(pid).ok()?.stat().ok()?;
+    (!matches!(stat.state, 'Z' | 'X')).then_some(stat.starttime)
+}
+
 fn self_daemonize(root: &str) -> Result<()> {
     sys::stat::umask(sys::stat::Mode::empty());
 
@@ -0,0 +1,398 @@
+// Checks run before a thaw, so that an image that can't be restored here is turned away up front
+// with a list of what is wrong, instead of leaving a half-restored process behind.
+
+use std::fmt::Write;
+
+use anyhow::Result;
+use nix::{
+    sys::{self, signal::Signal},
+    unistd::Pid,
+};
+
+use crate::{
+    common::{arch, elf, restorer, vdso},
+    proctool::{
+        cryogenics::Checkpoint,
+        pcontroller::{ProcessController, SVC_REGION_SIZE},
+    },
+    teleclient::myprocfs::{self, MemoryMap},
+};
+
+#[derive(Default, Debug)]
+pub struct Report {
+    /// reasons the image can't be thawed
+    pub errors: Vec<String>,
+    /// things that may go wrong in the thawed process
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn format(&self) -> String {
+        let mut r = String::new();
+        for error in self.errors.iter() {
+            writeln!(r, "error: {}", error).unwrap();
+        }
+        for warning in self.warnings.iter() {
+            writeln!(r, "warning: {}", warning).unwrap();
+        }
+        if self.errors.is_empty() && self.warnings.is_empty() {
+            writeln!(r, "no problems found").unwrap();
+        }
+        r
+    }
+}
+
+/// checks whether `checkpoint` can be thawed on this machine
+pub fn check(checkpoint: &Checkpoint) -> Result<Report> {
+    let mut report = Report::default();
+    check_machine(checkpoint, procfs::page_size(), &mut report);
+    check_files(checkpoint, &mut report);
+
+    // a thawed process starts out as a restorer, which keeps the kernel's own mappings, so a
+    // fresh one shows where those will be and has the vDSO it will get
+    let pid = restorer::spawn()?;
+    let restorer_maps = myprocfs::read_memory_maps(pid.as_raw());
+    let kernel_vdso = read_vdso(pid);
+    let _ = sys::signal::kill(pid, Signal::SIGKILL);
+    let _ = sys::wait::waitpid(pid, None);
+    let restorer_maps = restorer_maps?;
+    let kernel_vdso = kernel_vdso?;
+
+    // placed the way `pcontroller::prepare_restorer` places it
+    let taken: Vec<(u64, u64)> = checkpoint
+        .processes
+        .iter()
+        .flat_map(|state| state.memory_maps.iter())
+        .chain(restorer_maps.iter())
+        .map(|map| (map.base_address, map.base_address + map.size))
+        .collect();
+    let svc_region_addr = restorer::find_free_address(&taken, SVC_REGION_SIZE);
+    let svc_region = (svc_region_addr, svc_region_addr + SVC_REGION_SIZE);
+    check_collisions(checkpoint, &restorer_maps, svc_region, &mut report);
+
+    let kernel_vdso = kernel_vdso
+        .as_ref()
+        .map(|(base, image)| (*base, &image[..]));
+    check_vdso(checkpoint, kernel_vdso, &mut report);
+
+    Ok(report)
+}
+
+fn read_vdso(pid: Pid) -> Result<Option<(u64, Vec<u8>)>> {
+    let maps = myprocfs::read_memory_maps(pid.as_raw())?;
+    match maps.iter().find(|map| map.label == "[vdso]") {
+        Some(map) => {
+            let image =
+                ProcessController::new(pid).read_bytes(map.base_address, map.size as usize)?;
+            Ok(Some((map.base_address, image)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn check_machine(checkpoint: &Checkpoint, page_size: u64, report: &mut Report) {
+    if checkpoint.arch != arch::NAME {
+        report.errors.push(format!(
+            "the image was taken on {}, but this machine is {}",
+            checkpoint.arch,
+            arch::NAME
+        ));
+    }
+    if checkpoint.page_size != page_size {
+        report.errors.push(format!(
+            "the image was taken with {} byte pages, but this kernel uses {} byte pages",
+            checkpoint.page_size, page_size
+        ));
+    }
+}
+
+/// files that regions were saved by reference to have to be the very same ones
+fn check_files(checkpoint: &Checkpoint, report: &mut Report) {
+    // files already reported, and files whose build-id has been looked at, so that neither the
+    // error nor the ELF parsing is repeated for every mapping of the same file
+    let mut checked: Vec<&str> = Vec::new();
+    let mut build_ids_checked: Vec<&str> = Vec::new();
+    for state in checkpoint.processes.iter() {
+        for map in state.memory_maps.iter() {
+            let backing = match &map.file {
+                Some(backing) => backing,
+                None => continue,
+            };
+            if checked.contains(&map.label.as_str()) {
+                continue;
+            }
+
+            let build_id = if build_ids_checked.contains(&map.label.as_str()) {
+                None
+            } else {
+                build_ids_checked.push(&map.label);
+                Some(elf::read_build_id(&map.label))
+            };
+            let error = match (&backing.build_id, build_id) {
+                (_, Some(Err(e))) => Some(format!(
+                    "{}, mapped by process {}, can't be read: {}; put it back or thaw on a \
+                     machine that has it",
+                    map.label, state.pid, e
+                )),
+                (Some(was), Some(Ok(now))) if now.as_ref() != Some(was) => Some(format!(
+                    "{}, mapped by process {}, has build-id {} but the frozen process had {}; \
+                     reinstall the version it was running",
+                    map.label,
+                    state.pid,
+                    now.as_deref().unwrap_or("(none)"),
+                    was
+                )),
+                _ => backing.verify(map).err().map(|e| {
+                    format!(
+                        "{} (mapped by process {} at {:#x}); restore the file as it was",
+                        e, state.pid, map.base_address
+                    )
+                }),
+            };
+            if let Some(error) = error {
+                report.errors.push(error);
+                checked.push(&map.label);
+            }
+        }
+    }
+}
+
+/// regions in the way of what every restorer has mapped from the start: the vDSO and friends,
+/// and the svc region, at `svc_region`, that thawing injects its syscalls from
+fn check_collisions(
+    checkpoint: &Checkpoint,
+    restorer_maps: &[MemoryMap],
+    svc_region: (u64, u64),
+    report: &mut Report,
+) {
+    let kernel_maps: Vec<&MemoryMap> = restorer_maps
+        .iter()
+        .filter(|map| map.is_kernel_provided())
+        .collect();
+
+    for state in checkpoint.processes.iter() {
+        // thawing moves the kernel's vDSO out of the way of everything before putting it where
+        // the saved one was
+        let moves_vdso = state.memory_maps.iter().any(|map| map.label == "[vdso]");
+        for map in state.memory_maps.iter() {
+            if map.is_kernel_provided() {
+                continue;
+            }
+            let end = map.base_address + map.size;
+            let overlaps =
+                |(start, other_end): (u64, u64)| map.base_address < other_end && start < end;
+
+            if overlaps(svc_region) {
+                report.errors.push(format!(
+                    "{} at {:#x}-{:#x} in process {} overlaps the svc region of a new process at \
+                     {:#x}-{:#x}",
+                    map.label, map.base_address, end, state.pid, svc_region.0, svc_region.1
+                ));
+            }
+            for kernel_map in kernel_maps.iter() {
+                let kernel_end = kernel_map.base_address + kernel_map.size;
+                if !overlaps((kernel_map.base_address, kernel_end)) {
+                    continue;
+                }
+                let (problems, advice) = if kernel_map.label == "[vsyscall]" {
+                    (
+                        &mut report.errors,
+                        "which is in the same place in every process",
+                    )
+                } else if moves_vdso {
+                    continue;
+                } else {
+                    (
+                        &mut report.warnings,
+                        "it is placed at random, so trying again may avoid it",
+                    )
+                };
+                problems.push(format!(
+                    "{} at {:#x}-{:#x} in process {} overlaps the {} of a new process at \
+                     {:#x}-{:#x}, {}",
+                    map.label,
+                    map.base_address,
+                    end,
+                    state.pid,
+                    kernel_map.label,
+                    kernel_map.base_address,
+                    kernel_end,
+                    advice
+                ));
+            }
+        }
+    }
+}
+
+/// whether the kernel's vDSO, at `kernel_vdso`, can stand in for the one each process had; see
+/// `vdso`
+fn check_vdso(checkpoint: &Checkpoint, kernel_vdso: Option<(u64, &[u8])>, report: &mut Report) {
+    let mut checked: Vec<Vec<u8>> = Vec::new();
+    for state in checkpoint.processes.iter() {
+        let old = match state.memory_maps.iter().find(|map| map.label == "[vdso]") {
+            Some(old) => vdso::saved_image(old),
+            None => continue,
+        };
+        if checked.contains(&old) {
+            continue;
+        }
+        let (base, new) = match kernel_vdso {
+            Some(kernel_vdso) => kernel_vdso,
+            None => {
+                report.warnings.push(format!(
+                    "process {} used the vDSO, but this kernel doesn't provide one; calls into \
+                     it, such as clock_gettime(), will crash",
+                    state.pid
+                ));
+                continue;
+            }
+        };
+
+        // if it doesn't line up, thawing redirects what it can
+        match vdso::redirect(&mut old.clone(), new, base) {
+            Err(e) => report.errors.push(format!(
+                "the vDSO of process {} can't be made to work here: {}",
+                state.pid, e
+            )),
+            Ok(missing) if !missing.is_empty() => report.warnings.push(format!(
+                "the vDSO of process {} has {}, which this kernel's doesn't; calls to it will \
+                 crash",
+                state.pid,
+                missing.join(", ")
+            )),
+            Ok(_) => (),
+        }
+        checked.push(old);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proctool::image::tests::make_process,
+        teleclient::myprocfs::{FileBacking, PageRun},
+    };
+
+    fn checkpoint() -> Checkpoint {
+        Checkpoint::new(None, vec![make_process(100, 1)])
+    }
+
+    #[test]
+    fn test_check_machine() {
+        let mut report = Report::default();
+        check_machine(&checkpoint(), procfs::page_size(), &mut report);
+        assert!(report.is_ok());
+
+        let mut other = checkpoint();
+        other.arch = "pdp11".to_string();
+        check_machine(&other, 0x10000, &mut report);
+        assert_eq!(report.errors.len(), 2);
+        assert!(report.errors[0].contains("pdp11"));
+    }
+
+    #[test]
+    fn test_check_missing_file() {
+        let mut checkpoint = checkpoint();
+        let map = &mut checkpoint.processes[0].memory_maps[0];
+        map.label = "/nonexistent/libfoo.so".to_string();
+        map.file = Some(FileBacking {
+            dev: 0,
+            ino: 0,
+            mtime: 0,
+            mtime_nsec: 0,
+            hash: 0,
+            build_id: Some("abcd".to_string()),
+        });
+
+        let mut report = Report::default();
+        check_files(&checkpoint, &mut report);
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].contains("/nonexistent/libfoo.so"));
+    }
+
+    #[test]
+    fn test_check_collisions() {
+        let mut checkpoint = checkpoint();
+        // the heap is at 0x1000-0x3000 and [vvar] at 0x8000-0x9000
+        let kernel_map = |label: &str| {
+            let mut map = make_process(1, 0).memory_maps.remove(1);
+            map.label = label.to_string();
+            map.base_address = 0x2000;
+            map
+        };
+        let far = (0x100000, 0x101000);
+
+        let mut report = Report::default();
+        check_collisions(&checkpoint, &[kernel_map("[vdso]")], far, &mut report);
+        assert!(report.is_ok());
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("[heap]"));
+
+        // the new process's [vvar] being where the old one was is fine
+        let vvar = make_process(1, 0).memory_maps.remove(1);
+        let mut report = Report::default();
+        check_collisions(&checkpoint, &[vvar], far, &mut report);
+        assert!(report.is_ok() && report.warnings.is_empty());
+
+        let mut report = Report::default();
+        check_collisions(
+            &checkpoint,
+            &[kernel_map("[vsyscall]")],
+            (0x2000, 0x3000),
+            &mut report,
+        );
+        assert_eq!(report.errors.len(), 2);
+        assert!(report.errors[0].contains("svc region"));
+        assert!(report.errors[1].contains("[vsyscall]"));
+
+        // a saved vDSO has the kernel's moved out of the way
+        checkpoint.processes[0].memory_maps[1].label = "[vdso]".to_string();
+        let mut report = Report::default();
+        check_collisions(&checkpoint, &[kernel_map("[vdso]")], far, &mut report);
+        assert!(report.is_ok() && report.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_check_vdso() {
+        let maps = myprocfs::read_memory_maps(std::process::id() as i32).unwrap();
+        let ours = maps.iter().find(|map| map.label == "[vdso]").unwrap();
+        let p = ours.base_address as *const u8;
+        let image = unsafe { std::slice::from_raw_parts(p, ours.size as usize) }.to_vec();
+
+        let mut checkpoint = checkpoint();
+        let map = &mut checkpoint.processes[0].memory_maps[1];
+        map.label = "[vdso]".to_string();
+        map.size = ours.size;
+        map.runs = vec![PageRun {
+            offset: 0,
+            len: ours.size,
+        }];
+        map.data = image.clone();
+
+        let mut report = Report::default();
+        check_vdso(&checkpoint, Some((ours.base_address, &image)), &mut report);
+        assert!(report.is_ok() && report.warnings.is_empty());
+
+        check_vdso(&checkpoint, None, &mut report);
+        assert_eq!(report.warnings.len(), 1);
+
+        // every process is warned about, not just the first
+        let mut child = make_process(101, 100);
+        child.memory_maps[1].label = "[vdso]".to_string();
+        checkpoint.processes.push(child);
+        let mut report = Report::default();
+        check_vdso(&checkpoint, None, &mut report);
+        assert_eq!(report.warnings.len(), 2);
+        checkpoint.processes.pop();
+
+        // not a vDSO at all
+        checkpoint.processes[0].memory_maps[1].data = vec![0; ours.size as usize];
+        check_vdso(&checkpoint, Some((ours.base_address, &image)), &mut report);
+        assert_eq!(report.errors.len(), 1);
+    }
+}
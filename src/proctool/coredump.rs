@@ -0,0 +1,242 @@
+// Writes a frozen process out as an ELF core file, so that it can be opened in gdb alongside its
+// executable. Only what gdb needs for a backtrace is included: the memory, and the registers and
+// auxiliary vector as notes.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    common::{arch, elf},
+    proctool::cryogenics::{ProcessState, ThreadState},
+    teleclient::myprocfs::MemoryMap,
+};
+
+// note types from <linux/elf.h>
+const NT_PRSTATUS: u32 = 1;
+const NT_PRFPREG: u32 = 2;
+const NT_AUXV: u32 = 6;
+
+pub fn save(path: &str, state: &ProcessState, page_size: u64) -> Result<()> {
+    let f = File::create(path).map_err(|e| anyhow!("could not create {}: {}", path, e))?;
+    let mut writer = BufWriter::new(f);
+    write_core(&mut writer, state, page_size)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn write_core<W: Write + Seek>(
+    writer: &mut W,
+    state: &ProcessState,
+    page_size: u64,
+) -> Result<()> {
+    let notes = notes(state);
+    let phnum = 1 + state.memory_maps.len();
+    let notes_offset = (elf::EHDR_SIZE + elf::PHDR_SIZE * phnum) as u64;
+
+    writer.write_all(&elf::file_header(elf::ET_CORE, 0, phnum as u16))?;
+    let note_header = elf::ProgramHeader {
+        p_type: elf::PT_NOTE,
+        flags: 0,
+        offset: notes_offset,
+        vaddr: 0,
+        filesz: notes.len() as u64,
+        memsz: 0,
+        align: 4,
+    };
+    writer.write_all(&note_header.to_bytes())?;
+
+    // the segments follow the notes, page-aligned
+    let mut offset = (notes_offset + notes.len() as u64).next_multiple_of(page_size);
+    let mut segments = Vec::new();
+    for map in state.memory_maps.iter() {
+        // like the kernel, leave out the contents of regions that can't be read
+        let filesz = if map.readable { map.size } else { 0 };
+        let header = elf::ProgramHeader {
+            p_type: elf::PT_LOAD,
+            flags: segment_flags(map),
+            offset,
+            vaddr: map.base_address,
+            filesz,
+            memsz: map.size,
+            align: page_size,
+        };
+        writer.write_all(&header.to_bytes())?;
+        if filesz > 0 {
+            segments.push((offset, map));
+        }
+        offset += filesz;
+    }
+    writer.write_all(&notes)?;
+
+    for (offset, map) in segments {
+        write_contents(writer, offset, map)?;
+    }
+
+    // parts of the regions that were never stored are left as holes, so make sure the file is
+    // long enough to contain them
+    let end = offset;
+    if writer.seek(SeekFrom::End(0))? < end {
+        writer.seek(SeekFrom::Start(end - 1))?;
+        writer.write_all(&[0])?;
+    }
+    Ok(())
+}
+
+fn segment_flags(map: &MemoryMap) -> u32 {
+    let mut r = 0;
+    if map.readable {
+        r |= elf::PF_R;
+    }
+    if map.writable {
+        r |= elf::PF_W;
+    }
+    if map.executable {
+        r |= elf::PF_X;
+    }
+    r
+}
+
+/// writes the region the way the process saw it: the file it maps, if it was saved by reference,
+/// with the stored pages on top
+fn write_contents<W: Write + Seek>(writer: &mut W, offset: u64, map: &MemoryMap) -> Result<()> {
+    if map.file.is_some() {
+        writer.seek(SeekFrom::Start(offset))?;
+        // if the file is gone the region is left as zeros, as it would be in the thawed process
+        if let Ok(mut file) = File::open(&map.label) {
+            file.seek(SeekFrom::Start(map.offset))?;
+            io::copy(&mut io::Read::take(file, map.size), writer)?;
+        }
+    }
+
+    for (addr, bytes) in map.populated_runs() {
+        writer.seek(SeekFrom::Start(offset + addr - map.base_address))?;
+        writer.write_all(bytes)?;
+    }
+    Ok(())
+}
+
+fn notes(state: &ProcessState) -> Vec<u8> {
+    // gdb takes the first NT_PRSTATUS to be the thread that stopped, and expects the notes that
+    // aren't per-thread to follow it
+    let mut r = Vec::new();
+    for (i, thread) in state.threads.iter().enumerate() {
+        r.extend(elf::note("CORE", NT_PRSTATUS, &prstatus(state, thread)));
+        let fp_registers = thread.fp_registers.as_bytes();
+        r.extend(elf::note(
+            "CORE",
+            NT_PRFPREG,
+            &fp_registers[..arch::PRFPREG_SIZE],
+        ));
+        // the rest of an XSAVE area, which gdb reads from a note of its own
+        if arch::FP_REGSET as u32 != NT_PRFPREG {
+            r.extend(elf::note("LINUX", arch::FP_REGSET as u32, fp_registers));
+        }
+        if i == 0 {
+            r.extend(elf::note("CORE", NT_AUXV, &state.memory_layout.auxv));
+        }
+    }
+    r
+}
+
+/// returns a struct elf_prstatus from <linux/elfcore.h>
+fn prstatus(state: &ProcessState, thread: &ThreadState) -> Vec<u8> {
+    let pending = thread
+        .pending_signals
+        .iter()
+        .chain(state.signals.pending.iter())
+        .map(|signal| signal.signal())
+        // an image can hold anything, and there are only bits for 1 to 64
+        .filter(|signal| (1..=64).contains(signal))
+        .fold(0u64, |mask, signal| mask | 1 << (signal - 1));
+
+    let mut r = Vec::new();
+    // pr_info, unused by gdb
+    r.extend_from_slice(&[0; 12]);
+    // pr_cursig; everything we save was stopped, if not always with SIGSTOP
+    r.extend_from_slice(&(libc::SIGSTOP as u16).to_le_bytes());
+    r.extend_from_slice(&[0; 2]);
+    r.extend_from_slice(&pending.to_le_bytes());
+    r.extend_from_slice(&thread.signal_mask.to_le_bytes());
+    for id in [thread.tid, state.ppid, state.pgid, state.sid] {
+        r.extend_from_slice(&id.to_le_bytes());
+    }
+    // pr_utime, pr_stime, pr_cutime and pr_cstime
+    r.extend_from_slice(&[0; 64]);
+    r.extend_from_slice(&thread.registers.to_elf_gregset());
+    // pr_fpvalid, then padding
+    r.extend_from_slice(&1i32.to_le_bytes());
+    r.extend_from_slice(&[0; 4]);
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        common::signals::{QueuedSignal, SIGINFO_SIZE},
+        proctool::image::tests::make_process,
+    };
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_prstatus_size() {
+        let state = make_process(100, 1);
+        let expected = if cfg!(target_arch = "x86_64") {
+            336
+        } else {
+            392
+        };
+        assert_eq!(prstatus(&state, &state.threads[0]).len(), expected);
+    }
+
+    #[test]
+    fn test_prstatus_pending() {
+        let mut state = make_process(100, 1);
+        for signal in [0, libc::SIGUSR1, 65] {
+            let mut siginfo = vec![0; SIGINFO_SIZE];
+            siginfo[..4].copy_from_slice(&signal.to_le_bytes());
+            state.signals.pending.push(QueuedSignal { siginfo });
+        }
+        // pr_sigpend comes after pr_info, pr_cursig and padding
+        let r = prstatus(&state, &state.threads[0]);
+        assert_eq!(u64_at(&r, 16), 1 << (libc::SIGUSR1 - 1));
+    }
+
+    #[test]
+    fn test_write_core() {
+        let state = make_process(100, 1);
+        let mut cursor = Cursor::new(Vec::new());
+        write_core(&mut cursor, &state, 0x1000).unwrap();
+        let core = cursor.into_inner();
+
+        assert_eq!(&core[..4], b"\x7fELF");
+        assert_eq!(u16::from_le_bytes([core[16], core[17]]), elf::ET_CORE);
+        // the notes, then the heap and [vvar]
+        assert_eq!(u16::from_le_bytes([core[56], core[57]]), 3);
+
+        let heap = elf::EHDR_SIZE + elf::PHDR_SIZE;
+        let heap_offset = u64_at(&core, heap + 8) as usize;
+        assert_eq!(u64_at(&core, heap + 16), 0x1000);
+        assert_eq!(u64_at(&core, heap + 32), 0x2000);
+        assert_eq!(heap_offset % 0x1000, 0);
+        assert!(core[heap_offset..heap_offset + 0x2000]
+            .iter()
+            .all(|b| *b == 7));
+
+        // nothing of [vvar] was stored, but it is readable, so it's there as zeros
+        let vvar = heap + elf::PHDR_SIZE;
+        let vvar_offset = u64_at(&core, vvar + 8) as usize;
+        assert_eq!(vvar_offset, heap_offset + 0x2000);
+        assert_eq!(core.len(), vvar_offset + 0x1000);
+        assert!(core[vvar_offset..].iter().all(|b| *b == 0));
+    }
+}
@@ -1,89 +1,697 @@
+use std::{collections::HashMap, fs};
+
 use anyhow::{anyhow, Result};
 use nix::{sys, unistd};
 use serde::{Deserialize, Serialize};
+use syscalls::Sysno;
 
 use crate::{
-    proctool::{pcontroller::ProcessController, terminals},
+    common::{
+        arch::{self, FpRegisters, Registers},
+        restorer,
+        signals::{self, QueuedSignal, SignalState},
+    },
+    proctool::{
+        attributes::{self, MemoryLayout, ProcessAttributes},
+        compat, fds,
+        lazy::{self, PageServer},
+        pcontroller::{self, ProcessController},
+        procinfo, terminals,
+    },
     teleclient::myprocfs,
 };
 
+/// one or more processes frozen together
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// `arch::NAME` of the machine it was taken on
+    pub arch: String,
+    pub page_size: u64,
+    /// image this one only stores the changes since, relative to this image's directory; see
+    /// `image::load_chain`
+    pub parent: Option<String>,
+    /// parents come before their children
+    pub processes: Vec<ProcessState>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ProcessState {
+    /// ids at the time of the freeze; the thawed process will get a new pid
+    pub pid: i32,
+    pub ppid: i32,
+    pub pgid: i32,
+    pub sid: i32,
     pub memory_maps: Vec<myprocfs::MemoryMap>,
-    // from libc::user_regs_struct
-    // very much ARM64 specific
-    pub regs: [u64; 31],
-    pub sp: u64,
-    pub pc: u64,
-    pub pstate: u64,
+    /// the thread group leader comes first
+    pub threads: Vec<ThreadState>,
+    pub open_files: Vec<fds::OpenFile>,
+    pub signals: SignalState,
+    pub attributes: ProcessAttributes,
+    pub memory_layout: MemoryLayout,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ThreadState {
+    pub tid: i32,
+    pub registers: Registers,
+    pub fp_registers: FpRegisters,
+    pub signal_mask: u64,
+    /// (head, len) as passed to set_robust_list(2)
+    pub robust_list: (u64, u64),
+    /// as passed to set_tid_address(2); the kernel clears it and wakes it when the thread exits,
+    /// which is what pthread_join() waits for
+    pub clear_child_tid: u64,
+    /// signals queued for this thread in particular
+    pub pending_signals: Vec<QueuedSignal>,
+}
+
+impl Checkpoint {
+    pub fn new(parent: Option<String>, processes: Vec<ProcessState>) -> Self {
+        Self {
+            arch: arch::NAME.to_string(),
+            page_size: procfs::page_size(),
+            parent,
+            processes,
+        }
+    }
+
+    fn children_of(&self, pid: i32) -> impl Iterator<Item = &ProcessState> {
+        self.processes.iter().filter(move |p| p.ppid == pid)
+    }
+
+    /// processes whose parent isn't part of the checkpoint
+    fn roots(&self) -> impl Iterator<Item = &ProcessState> {
+        self.processes
+            .iter()
+            .filter(|p| !self.processes.iter().any(|parent| parent.pid == p.ppid))
+    }
+}
+
+/// freezes every process in `pids`, which must list parents before children
+pub fn freeze_all(pids: &[unistd::Pid]) -> Result<Checkpoint> {
+    // stop everything up front so that the processes are captured at the same point, rather than
+    // one of them carrying on talking to another we have already frozen
+    let result = pids
+        .iter()
+        .try_for_each(|pid| {
+            sys::signal::kill(*pid, sys::signal::SIGSTOP)
+                .map_err(|e| anyhow!("could not stop process {}: {}", pid, e))
+        })
+        .and_then(|_| {
+            pids.iter()
+                .map(|pid| freeze(*pid))
+                .collect::<Result<Vec<_>>>()
+        });
+
+    // don't leave the ones that were already stopped hanging
+    if result.is_err() {
+        for pid in pids {
+            let _ = sys::signal::kill(*pid, sys::signal::SIGCONT);
+        }
+    }
+    Ok(Checkpoint::new(None, result?))
+}
+
+/// like `freeze_all`, but the processes carry on running afterwards as if nothing had happened
+///
+/// they are held with PTRACE_INTERRUPT rather than SIGSTOP, which their parents and shells would
+/// see as a job-control stop and continue
+pub fn freeze_all_running(pids: &[unistd::Pid]) -> Result<Checkpoint> {
+    // held up front as in `freeze_all`; they are let go once `held` is dropped
+    let held = pids
+        .iter()
+        .map(|pid| attach_threads(*pid, Attach::Seize))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut processes = Vec::new();
+    for (pid, controllers) in pids.iter().zip(held.iter()) {
+        processes.push(capture(*pid, controllers, None)?);
+    }
+    Ok(Checkpoint::new(None, processes))
+}
+
+/// snapshots a process and lets it carry on running, like `freeze_all_running`
+///
+/// given the checkpoint before this one and its path, only the pages written to since then are
+/// saved; either way, soft-dirty tracking is reset so that the next checkpoint can build on this
+/// one
+pub fn checkpoint(pid: unistd::Pid, parent: Option<(&str, &Checkpoint)>) -> Result<Checkpoint> {
+    let parent_state = match parent {
+        Some((path, checkpoint)) => Some(
+            checkpoint
+                .processes
+                .iter()
+                .find(|p| p.pid == pid.as_raw())
+                .ok_or(anyhow!("{} is not a checkpoint of process {}", path, pid))?,
+        ),
+        None => None,
+    };
+
+    let controllers = attach_threads(pid, Attach::Seize)?;
+    let state = capture(pid, &controllers, parent_state)?;
+    // while it is still held, so that no write goes unnoticed
+    myprocfs::clear_soft_dirty(pid)?;
+    Ok(Checkpoint::new(
+        parent.map(|(path, _)| path.to_string()),
+        vec![state],
+    ))
+}
+
+/// snapshots a process that was stopped with SIGSTOP, and leaves it stopped
 pub fn freeze(pid: unistd::Pid) -> Result<ProcessState> {
-    let controller = ProcessController::new(pid);
-    controller.attach()?;
-    let registers = controller.get_registers()?;
-    controller.detach_and_stop()?;
+    let controllers = attach_threads(pid, Attach::Attach)?;
+    let state = capture(pid, &controllers, None);
+    for controller in controllers.iter() {
+        controller.detach_and_stop()?;
+    }
+    state
+}
 
+#[derive(Clone, Copy)]
+enum Attach {
+    /// PTRACE_ATTACH, for a process that is already stopped
+    Attach,
+    /// PTRACE_SEIZE and PTRACE_INTERRUPT, which stop a process without it being able to tell
+    Seize,
+}
+
+/// attaches to and stops every thread of `pid`, leader first
+fn attach_threads(pid: unistd::Pid, how: Attach) -> Result<Vec<ProcessController>> {
+    // attaching only stops one thread, so every thread has to be attached before any of them are
+    // read; the ones still running can start more in the meantime, so look again until no new
+    // ones turn up
+    let mut controllers: Vec<ProcessController> = Vec::new();
+    loop {
+        let new: Vec<unistd::Pid> = read_thread_ids(pid)?
+            .into_iter()
+            .filter(|tid| !controllers.iter().any(|c| c.pid() == *tid))
+            .collect();
+        if new.is_empty() {
+            break;
+        }
+        for tid in new {
+            let controller = ProcessController::new(tid);
+            match how {
+                Attach::Attach => controller.attach()?,
+                Attach::Seize => controller.seize()?,
+            }
+            controllers.push(controller);
+        }
+    }
+    controllers.sort_by_key(|c| (c.pid() != pid, c.pid().as_raw()));
+    Ok(controllers)
+}
+
+/// reads the state of `pid`, every thread of which `controllers` has stopped
+fn capture(
+    pid: unistd::Pid,
+    controllers: &[ProcessController],
+    parent: Option<&ProcessState>,
+) -> Result<ProcessState> {
+    let info = procinfo::get_process_info(pid.as_raw())?;
+
+    // anything that arrived while the threads were stopped has to stay pending rather than go off
+    // in the middle of the syscalls injected to read them, and has to be left pending afterwards
+    // whether that worked or not
+    let masks = controllers
+        .iter()
+        .map(|controller| controller.get_signal_mask())
+        .collect::<Result<Vec<_>>>()?;
+    let result = controllers
+        .iter()
+        .try_for_each(|controller| controller.set_signal_mask(signals::ALL_SIGNALS))
+        .and_then(|_| read_threads(controllers, &masks))
+        .and_then(|r| {
+            // before the memory is read, which it would otherwise end up in
+            controllers
+                .iter()
+                .try_for_each(|controller| controller.remove_svc_page())?;
+            Ok(r)
+        });
+    for (controller, mask) in controllers.iter().zip(masks.iter()) {
+        controller.set_signal_mask(*mask)?;
+    }
+    let (threads, signals, brk) = result?;
+
+    let open_files = fds::read_open_files(pid.as_raw())?;
+    let attributes = attributes::read_attributes(pid.as_raw())?;
+    let memory_layout = attributes::read_memory_layout(pid.as_raw(), brk)?;
     let mut memory_maps = myprocfs::read_memory_maps(pid.as_raw())?;
     println!("reading process memory... this may take a while");
-    myprocfs::populate_memory(pid, &mut memory_maps)?;
+    match parent {
+        Some(parent) => {
+            let in_parent = |memory_map: &myprocfs::MemoryMap| {
+                parent.memory_maps.iter().any(|p| {
+                    p.base_address == memory_map.base_address
+                        && p.size == memory_map.size
+                        && p.label == memory_map.label
+                })
+            };
+            myprocfs::populate_memory_incremental(pid, &mut memory_maps, &in_parent)?
+        }
+        None => myprocfs::populate_memory_by_reference(pid, &mut memory_maps)?,
+    }
 
     Ok(ProcessState {
+        pid: pid.as_raw(),
+        ppid: info.ppid,
+        pgid: info.pgid,
+        sid: info.sid,
         memory_maps,
-        regs: registers.regs,
-        sp: registers.sp,
-        pc: registers.pc,
-        pstate: registers.pstate,
+        threads,
+        open_files,
+        signals,
+        attributes,
+        memory_layout,
     })
 }
 
-pub fn thaw(state: &ProcessState) -> Result<()> {
-    match unsafe { unistd::fork() }? {
-        unistd::ForkResult::Parent { child } => {
-            println!("child pid: {}", child);
-            sys::wait::waitpid(child, Some(sys::wait::WaitPidFlag::WSTOPPED))
-                .map_err(|e| anyhow!("failed to waitpid: {}", e))?;
-
-            let controller = ProcessController::new(child);
-            let svc_region_addr = controller
-                .map_svc_region()
-                .map_err(|e| anyhow!("failed to map svc region: {}", e))?;
-
-            for map in state.memory_maps.iter() {
-                println!(
-                    "mapping memory region at {:#x} (size={})",
-                    map.base_address, map.size
-                );
-                if let Err(e) = controller
-                    .map_and_fill_region(svc_region_addr, map)
-                    .map_err(|e| {
-                        anyhow!(
-                            "failed to map/fill memory region (addr={:#x}): {}",
-                            map.base_address,
-                            e
-                        )
-                    })
-                {
-                    println!("error: {}", e);
-                }
+/// returns the state of each thread, the signal state of the process and its program break
+fn read_threads(
+    controllers: &[ProcessController],
+    masks: &[u64],
+) -> Result<(Vec<ThreadState>, SignalState, u64)> {
+    let mut threads = Vec::new();
+    for (controller, mask) in controllers.iter().zip(masks.iter()) {
+        threads.push(ThreadState {
+            tid: controller.pid().as_raw(),
+            registers: controller.get_registers()?,
+            fp_registers: controller.get_fp_registers()?,
+            signal_mask: *mask,
+            robust_list: controller.get_robust_list()?,
+            clear_child_tid: controller.get_clear_child_tid()?,
+            pending_signals: controller.get_pending_signals(false)?,
+        });
+    }
+
+    // signal actions are shared by every thread, so asking the leader is enough
+    let signals = SignalState {
+        actions: controllers[0].get_signal_actions()?,
+        pending: controllers[0].get_pending_signals(true)?,
+    };
+    let brk = controllers[0].get_brk()?;
+    Ok((threads, signals, brk))
+}
+
+#[derive(Default)]
+pub struct ThawOptions {
+    /// give every process and thread its original id back, or fail
+    pub same_pid: bool,
+    /// thaw into a new pid namespace, where the original ids are sure to be free; implies
+    /// `same_pid`
+    pub new_pid_namespace: bool,
+    /// leave most of the memory to be filled in once the processes are running; see `lazy`
+    pub lazy: bool,
+    /// run `compat::check` before anything is started
+    pub check: bool,
+}
+
+pub fn thaw(checkpoint: &Checkpoint, options: &ThawOptions) -> Result<()> {
+    if options.check {
+        let report = compat::check(checkpoint)?;
+        print!("{}", report.format());
+        if !report.is_ok() {
+            return Err(anyhow!("this image can't be thawed here"));
+        }
+    }
+
+    let mut thawed = Thawed {
+        pids: HashMap::new(),
+        controllers: Vec::new(),
+        same_pid: options.same_pid || options.new_pid_namespace,
+        lazy: options.lazy,
+        page_servers: Vec::new(),
+    };
+
+    // children are forked from their thawed parents and inherit the svc region, so it has to be
+    // clear of every process's memory
+    let reserved: Vec<(u64, u64)> = checkpoint
+        .processes
+        .iter()
+        .flat_map(|state| state.memory_maps.iter())
+        .map(|map| (map.base_address, map.base_address + map.size))
+        .collect();
+
+    // pid 1 of the new namespace; it forks the roots, then stays behind to reap orphans
+    let init = if options.new_pid_namespace {
+        let pid = restorer::spawn_in_new_pid_namespace()?;
+        Some(pcontroller::prepare_restorer(pid, &reserved)?)
+    } else {
+        None
+    };
+
+    let mut roots = Vec::new();
+    for state in checkpoint.roots() {
+        let (controller, svc_region_addr) = match &init {
+            Some((init, svc_region_addr)) => {
+                let pid = unistd::Pid::from_raw(state.pid);
+                let child = init.fork_process(*svc_region_addr, Some(pid))?;
+                (ProcessController::new(child), *svc_region_addr)
+            }
+            None => {
+                let pid = if thawed.same_pid {
+                    restorer::spawn_with_pid(state.pid)?
+                } else {
+                    restorer::spawn()?
+                };
+                pcontroller::prepare_restorer(pid, &reserved)
+                    .map_err(|e| anyhow!("failed to prepare restorer: {}", e))?
             }
+        };
+        let child = controller.pid();
+        println!("child pid: {}", child);
 
-            controller.set_registers(libc::user_regs_struct {
-                regs: state.regs,
-                sp: state.sp,
-                pc: state.pc,
-                pstate: state.pstate,
-            })?;
+        thaw_process(checkpoint, state, controller, svc_region_addr, &mut thawed)?;
+        roots.push(child);
+    }
+
+    let page_servers = std::mem::take(&mut thawed.page_servers);
+    std::thread::scope(|scope| {
+        // the servers have to be up before anything runs, or the first fault would hang
+        for server in page_servers {
+            scope.spawn(move || {
+                if let Err(e) = server.run() {
+                    println!("error: {}", e);
+                }
+            });
+        }
 
-            // TODO:
-            terminals::clear_terminal("/dev/tty")?;
+        // TODO:
+        terminals::clear_terminal("/dev/tty")?;
+        for controller in thawed.controllers.iter() {
             controller.detach()?;
-            // controller.detach_and_stop()?;
-            controller.waitpid()?;
         }
-        unistd::ForkResult::Child => {
-            sys::ptrace::traceme()?;
-            sys::signal::raise(sys::signal::SIGSTOP)?;
+        // controller.detach_and_stop()?;
+
+        // the roots are children of the namespace's init rather than ours, and it only exits
+        // once they are all gone
+        if let Some((init, svc_region_addr)) = init {
+            init.become_reaper(svc_region_addr)?;
+            init.detach()?;
+            roots = vec![init.pid()];
+        }
+        for root in roots {
+            sys::wait::waitpid(root, None).map_err(|e| anyhow!("failed to waitpid: {}", e))?;
+        }
+
+        Ok(())
+    })
+}
+
+/// everything restored so far, kept attached until the whole checkpoint is in place
+struct Thawed<'a> {
+    /// original pid to new pid
+    pids: HashMap<i32, unistd::Pid>,
+    controllers: Vec<ProcessController>,
+    /// whether everything got its original id back
+    same_pid: bool,
+    lazy: bool,
+    /// one for each process with memory still to fill in, started just before the processes are
+    /// let go
+    page_servers: Vec<PageServer<'a>>,
+}
+
+impl Thawed<'_> {
+    /// returns the id the process or thread `original` now has as seen from its own pid
+    /// namespace, given its id `new` as seen from ours
+    fn id_inside(&self, original: i32, new: unistd::Pid) -> unistd::Pid {
+        if self.same_pid {
+            unistd::Pid::from_raw(original)
+        } else {
+            new
+        }
+    }
+}
+
+/// none of these are worth giving up the thaw over, so failures are only reported
+fn restore_attributes(
+    controller: &ProcessController,
+    svc_region_addr: u64,
+    attributes: &ProcessAttributes,
+) {
+    if let Err(e) = controller.set_cwd(svc_region_addr, &attributes.cwd) {
+        println!("error: failed to restore cwd: {}", e);
+    }
+    if let Err(e) = controller.set_umask(svc_region_addr, attributes.umask) {
+        println!("error: failed to restore umask: {}", e);
+    }
+    for limit in &attributes.rlimits {
+        // raising a hard limit needs CAP_SYS_RESOURCE
+        if let Err(e) = controller.set_rlimit(svc_region_addr, limit) {
+            println!("error: failed to restore rlimit {}: {}", limit.resource, e);
+        }
+    }
+    if let Err(e) = controller.set_name(svc_region_addr, &attributes.comm) {
+        println!("error: failed to restore process name: {}", e);
+    }
+}
+
+/// turns the stopped process behind `controller` into `state`, then forks its children from it
+fn thaw_process<'a>(
+    checkpoint: &'a Checkpoint,
+    state: &'a ProcessState,
+    controller: ProcessController,
+    svc_region_addr: u64,
+    thawed: &mut Thawed<'a>,
+) -> Result<()> {
+    thawed.pids.insert(state.pid, controller.pid());
+
+    // the signals queued below must not go off in the middle of an injected syscall; the
+    // threads get their own masks back in `restore_thread`, after their last one
+    controller.set_signal_mask(signals::ALL_SIGNALS)?;
+
+    if let Err(e) = controller.relocate_vdso(svc_region_addr, &state.memory_maps) {
+        println!("error: failed to relocate the vDSO: {}", e);
+    }
+
+    let mut lazy_maps = Vec::new();
+    for map in state.memory_maps.iter() {
+        if map.is_kernel_provided() {
+            continue;
+        }
+        println!(
+            "mapping memory region at {:#x} (size={})",
+            map.base_address, map.size
+        );
+        let r = if thawed.lazy && lazy::is_lazy(map, state) {
+            lazy_maps.push(map);
+            controller.map_region_without_contents(svc_region_addr, map)
+        } else {
+            controller.map_and_fill_region(svc_region_addr, map)
+        };
+        if let Err(e) = r.map_err(|e| {
+            anyhow!(
+                "failed to map/fill memory region (addr={:#x}): {}",
+                map.base_address,
+                e
+            )
+        }) {
+            println!("error: {}", e);
+        }
+    }
+
+    // a later brk() from the old heap break would land on top of the restored memory
+    if let Err(e) = controller.set_memory_layout(svc_region_addr, &state.memory_layout) {
+        println!("error: failed to restore brk and mm fields: {}", e);
+    }
+
+    restore_open_files(&controller, svc_region_addr, &state.open_files)?;
+    restore_attributes(&controller, svc_region_addr, &state.attributes);
+    if let Err(e) = controller.set_signal_actions(svc_region_addr, &state.signals.actions) {
+        println!("error: failed to restore signal actions: {}", e);
+    }
+    if let Err(e) = restore_process_group(&controller, svc_region_addr, state, thawed) {
+        println!(
+            "error: failed to restore process group of {}: {}",
+            state.pid, e
+        );
+    }
+
+    // children inherit our memory, fds, process group and session, so now is the time to fork
+    // them, before the registers are restored
+    for child_state in checkpoint.children_of(state.pid) {
+        let pid = thawed
+            .same_pid
+            .then(|| unistd::Pid::from_raw(child_state.pid));
+        let child = controller.fork_process(svc_region_addr, pid)?;
+        println!("process {} is now {}", child_state.pid, child);
+        let mut child_controller = ProcessController::new(child);
+        // the child starts out with all of our memory, which isn't necessarily where its own goes
+        child_controller
+            .unmap_all_except_svc_region(svc_region_addr)
+            .map_err(|e| anyhow!("failed to clear process {}: {}", child, e))?;
+        thaw_process(
+            checkpoint,
+            child_state,
+            child_controller,
+            svc_region_addr,
+            thawed,
+        )?;
+    }
+
+    let (leader, others) = state
+        .threads
+        .split_first()
+        .ok_or(anyhow!("process state has no threads"))?;
+
+    // clone() copies the caller's registers, so the other threads have to be started before the
+    // leader's are restored
+    // ids as the process itself knows them, for queueing signals
+    let mut tids = vec![(leader, thawed.id_inside(leader.tid, controller.pid()))];
+    for thread in others {
+        let tid = thawed.same_pid.then(|| unistd::Pid::from_raw(thread.tid));
+        let tid = controller.clone_thread(svc_region_addr, tid)?;
+        println!("thread {} is now {}", thread.tid, tid);
+        let thread_controller = ProcessController::new(tid);
+        restore_thread(&thread_controller, svc_region_addr, thread)?;
+        thawed.controllers.push(thread_controller);
+        tids.push((thread, thawed.id_inside(thread.tid, tid)));
+    }
+
+    // only now that every thread exists can signals be queued for them
+    let pending = state
+        .signals
+        .pending
+        .iter()
+        .map(|signal| (None, signal))
+        .chain(tids.iter().flat_map(|(thread, tid)| {
+            thread
+                .pending_signals
+                .iter()
+                .map(move |signal| (Some(*tid), signal))
+        }));
+    for (tid, signal) in pending {
+        if let Err(e) = controller.queue_signal(svc_region_addr, tid, signal) {
+            println!("error: failed to queue signal {}: {}", signal.signal(), e);
+        }
+    }
+
+    // last, so that nothing we inject runs into a missing page with no one to serve it yet
+    if !lazy_maps.is_empty() {
+        let uffd = controller
+            .take_userfaultfd(svc_region_addr)
+            .map_err(|e| anyhow!("lazy thaw is not possible here: {}", e))?;
+        let server = PageServer::register(controller.pid(), uffd, &lazy_maps, procfs::page_size())?;
+        thawed.page_servers.push(server);
+    }
+
+    restore_thread(&controller, svc_region_addr, leader)?;
+    thawed.controllers.push(controller);
+    Ok(())
+}
+
+/// puts the thawed process back in a process group and session of its own if it used to lead
+/// one, or in the group of its thawed leader
+///
+/// processes that belonged to a group outside the checkpoint stay in ours
+fn restore_process_group(
+    controller: &ProcessController,
+    svc_region_addr: u64,
+    state: &ProcessState,
+    thawed: &Thawed,
+) -> Result<()> {
+    let (sysno, args) = if state.sid == state.pid {
+        (Sysno::setsid, vec![])
+    } else if state.pgid == state.pid {
+        (Sysno::setpgid, vec![0, 0])
+    } else if let Some(leader) = thawed.pids.get(&state.pgid) {
+        let leader = thawed.id_inside(state.pgid, *leader);
+        (Sysno::setpgid, vec![0, leader.as_raw() as i64])
+    } else {
+        return Ok(());
+    };
+
+    let r = controller.execute_syscall_at_pc(sysno, args, svc_region_addr)? as i64;
+    if r < 0 {
+        return Err(anyhow!(
+            "{} failed: {}",
+            sysno.name(),
+            std::io::Error::from_raw_os_error(-r as i32)
+        ));
+    }
+
+    // a root that leads its own group is now a background job in our session, and would be
+    // stopped as soon as it touched the terminal
+    if state.sid != state.pid
+        && state.pgid == state.pid
+        && !thawed.pids.contains_key(&state.ppid)
+        && unistd::isatty(0).unwrap_or(false)
+        && unsafe { libc::tcsetpgrp(0, controller.pid().as_raw()) } != 0
+    {
+        println!(
+            "warning: could not give the terminal to {}: {}",
+            state.pid,
+            std::io::Error::last_os_error()
+        );
+    }
+
+    Ok(())
+}
+
+fn read_thread_ids(pid: unistd::Pid) -> Result<Vec<unistd::Pid>> {
+    let dir = format!("/proc/{}/task", pid);
+    let mut tids = Vec::new();
+    for entry in fs::read_dir(&dir).map_err(|e| anyhow!("could not read {}: {}", dir, e))? {
+        if let Some(tid) = entry?
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<i32>().ok())
+        {
+            tids.push(tid);
+        }
+    }
+
+    // the leader's tid is the pid
+    tids.sort_by_key(|tid| (*tid != pid.as_raw(), *tid));
+    Ok(tids.into_iter().map(unistd::Pid::from_raw).collect())
+}
+
+fn restore_thread(
+    controller: &ProcessController,
+    svc_region_addr: u64,
+    thread: &ThreadState,
+) -> Result<()> {
+    // set_robust_list() and set_tid_address() only work on the calling thread, so they have to
+    // be injected
+    let (head, len) = thread.robust_list;
+    if head != 0 {
+        controller.execute_syscall_at_pc(
+            Sysno::set_robust_list,
+            vec![head as i64, len as i64],
+            svc_region_addr,
+        )?;
+    }
+
+    if thread.clear_child_tid != 0 {
+        controller.execute_syscall_at_pc(
+            Sysno::set_tid_address,
+            vec![thread.clear_child_tid as i64],
+            svc_region_addr,
+        )?;
+    }
+
+    // only now that nothing more is injected
+    controller.set_signal_mask(thread.signal_mask)?;
+    controller.set_registers(thread.registers)?;
+    controller.set_fp_registers(&thread.fp_registers)?;
+    Ok(())
+}
+
+fn restore_open_files(
+    controller: &ProcessController,
+    svc_region_addr: u64,
+    open_files: &[fds::OpenFile],
+) -> Result<()> {
+    // otherwise the thawed process would inherit whatever we had open
+    let keep: Vec<i32> = open_files.iter().map(|f| f.fd).collect();
+    controller.close_other_fds(svc_region_addr, &keep)?;
+
+    for open_file in open_files {
+        if let Err(e) = controller.restore_open_file(svc_region_addr, open_file) {
+            println!(
+                "error: failed to restore fd {} ({}): {}",
+                open_file.fd, open_file.path, e
+            );
         }
     }
 
@@ -0,0 +1,140 @@
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// a file descriptor that can be reopened by path on thaw
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OpenFile {
+    pub fd: i32,
+    pub path: String,
+    /// flags as passed to open(2), without O_CLOEXEC
+    pub flags: i32,
+    pub close_on_exec: bool,
+    pub offset: u64,
+}
+
+/// returns the process's file descriptors, skipping (with a warning) the ones that can't be
+/// reopened by path, like pipes, sockets and deleted files
+pub fn read_open_files(pid: i32) -> Result<Vec<OpenFile>> {
+    let dir = format!("/proc/{}/fd", pid);
+    let entries = fs::read_dir(&dir).map_err(|e| anyhow!("could not read {}: {}", dir, e))?;
+
+    let mut r = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let fd = match entry
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<i32>().ok())
+        {
+            Some(fd) => fd,
+            None => continue,
+        };
+
+        let target = fs::read_link(entry.path())?;
+        let path = target.to_string_lossy().to_string();
+        if !is_reopenable(&path) {
+            eprintln!("warning: not saving fd {} ({})", fd, path);
+            continue;
+        }
+
+        let fdinfo = fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd))?;
+        let (offset, flags) = parse_fdinfo(&fdinfo)?;
+        r.push(OpenFile {
+            fd,
+            path,
+            flags: flags & !libc::O_CLOEXEC,
+            close_on_exec: flags & libc::O_CLOEXEC != 0,
+            offset,
+        });
+    }
+
+    r.sort_by_key(|f| f.fd);
+    Ok(r)
+}
+
+/// regular files, directories, /dev/null and terminals
+fn is_reopenable(path: &str) -> bool {
+    // anything else is e.g. "pipe:[1234]", "socket:[1234]" or "anon_inode:[eventfd]"
+    if !path.starts_with('/') || path.ends_with(" (deleted)") {
+        return false;
+    }
+
+    if path == "/dev/null" || is_terminal(path) {
+        return true;
+    }
+
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.is_file() || metadata.is_dir(),
+        Err(_) => false,
+    }
+}
+
+/// /dev/tty, pseudo-terminals, virtual consoles (/dev/ttyN) and serial ports (/dev/ttySN)
+fn is_terminal(path: &str) -> bool {
+    let numbered = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match path.strip_prefix("/dev/tty") {
+        Some(rest) => {
+            rest.is_empty() || numbered(rest) || rest.strip_prefix('S').is_some_and(numbered)
+        }
+        None => path.starts_with("/dev/pts/"),
+    }
+}
+
+/// returns (offset, flags) from the contents of /proc/<pid>/fdinfo/<fd>
+fn parse_fdinfo(contents: &str) -> Result<(u64, i32)> {
+    let mut offset = None;
+    let mut flags = None;
+    for line in contents.lines() {
+        if let Some(value) = line.strip_prefix("pos:") {
+            offset = Some(
+                value
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|e| anyhow!("could not parse pos: {}", e))?,
+            );
+        } else if let Some(value) = line.strip_prefix("flags:") {
+            // printed in octal
+            flags = Some(
+                i32::from_str_radix(value.trim(), 8)
+                    .map_err(|e| anyhow!("could not parse flags: {}", e))?,
+            );
+        }
+    }
+
+    match (offset, flags) {
+        (Some(offset), Some(flags)) => Ok((offset, flags)),
+        _ => Err(anyhow!("fdinfo is missing pos or flags")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_reopenable, parse_fdinfo};
+
+    #[test]
+    fn test_parse_fdinfo() {
+        let contents = "pos:\t4096\nflags:\t02100002\nmnt_id:\t29\nino:\t1234\n";
+        let (offset, flags) = parse_fdinfo(contents).unwrap();
+        assert_eq!(offset, 4096);
+        assert_eq!(flags & libc::O_ACCMODE, libc::O_RDWR);
+        assert_ne!(flags & libc::O_CLOEXEC, 0);
+
+        assert!(parse_fdinfo("pos:\t0\n").is_err());
+    }
+
+    #[test]
+    fn test_is_reopenable() {
+        assert!(is_reopenable("/dev/null"));
+        assert!(is_reopenable("/dev/pts/3"));
+        assert!(is_reopenable("/dev/tty"));
+        assert!(is_reopenable("/dev/tty2"));
+        assert!(is_reopenable("/dev/ttyS0"));
+        assert!(!is_reopenable("/dev/ttyUSB0"));
+        assert!(is_reopenable("/"));
+        assert!(!is_reopenable("pipe:[58213]"));
+        assert!(!is_reopenable("socket:[58214]"));
+        assert!(!is_reopenable("/tmp/log.txt (deleted)"));
+    }
+}
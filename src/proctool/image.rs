@@ -0,0 +1,641 @@
+use std::{
+    fs,
+    io::{BufReader, BufWriter, Cursor, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{proctool::cryogenics::Checkpoint, teleclient::myprocfs};
+
+// Layout of a binary image (all integers little-endian):
+//
+//   magic              8 bytes, `MAGIC`
+//   format version     u32
+//   compression        u32, see `Compression`
+//   metadata length    u64
+//   metadata           JSON-encoded `Checkpoint` with every `MemoryMap::data` left empty
+//   region count       u64
+//   data lengths       one u64 per region
+//   data               `MemoryMap::data` for each region, back-to-back, in the order of
+//                      `ProcessState::memory_maps` of each process in turn; if compression is
+//                      enabled, each region is a series of compressed chunks, see
+//                      `compress_chunks`
+//
+// Keeping the page data out of the JSON is the whole point: serde_json writes every byte as a
+// decimal number, which made images several times larger than the process and slow to parse.
+//
+// All-zero pages never make it into `MemoryMap::data` in the first place (see
+// `myprocfs::sparsify`), so the data section is already sparse before any compression. Clean pages
+// of file-backed regions aren't stored at all; see `myprocfs::FileBacking`.
+
+pub const MAGIC: [u8; 8] = *b"PMIMAGE\0";
+pub const FORMAT_VERSION: u32 = 1;
+
+/// how much of a region is compressed at a time; see `compress_chunks`
+const COMPRESSION_CHUNK: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+pub enum Format {
+    Binary(Compression),
+    // human-readable, for debugging
+    Json,
+}
+
+/// saves `checkpoint` to a file only its owner can read, as it holds all of the processes' memory
+pub fn save(path: &str, checkpoint: Checkpoint, format: Format) -> Result<()> {
+    let f = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| anyhow!("could not create {}: {}", path, e))?;
+    let mut writer = BufWriter::new(f);
+    match format {
+        Format::Binary(compression) => write_image(&mut writer, checkpoint, compression)?,
+        Format::Json => serde_json::to_writer(&mut writer, &checkpoint)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// reads either format, telling them apart by the magic number
+pub fn load(path: &str) -> Result<Checkpoint> {
+    let f = fs::File::open(path).map_err(|e| anyhow!("could not open {}: {}", path, e))?;
+    let mut reader = BufReader::new(f);
+    read_image(&mut reader)
+}
+
+/// loads `path` and every image it was taken incrementally on top of, merged into one complete
+/// checkpoint
+pub fn load_chain(path: &str) -> Result<Checkpoint> {
+    load_chain_from(path, &mut Vec::new())
+}
+
+/// `visited` holds the images further down the chain, so that one naming its own descendant as
+/// its parent is an error rather than endless recursion
+fn load_chain_from(path: &str, visited: &mut Vec<PathBuf>) -> Result<Checkpoint> {
+    let canonical =
+        fs::canonicalize(path).map_err(|e| anyhow!("could not open {}: {}", path, e))?;
+    if visited.contains(&canonical) {
+        return Err(anyhow!("{} is its own ancestor", path));
+    }
+    visited.push(canonical);
+
+    let mut checkpoint = load(path)?;
+    if let Some(parent) = checkpoint.parent.take() {
+        let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+        let parent_path = dir.join(&parent);
+        let base = load_chain_from(&parent_path.to_string_lossy(), visited)
+            .map_err(|e| anyhow!("could not load parent image {}: {}", parent, e))?;
+        merge(&base, &mut checkpoint)?;
+    }
+    Ok(checkpoint)
+}
+
+/// fills in the pages `top` didn't store from the matching regions of `base`
+fn merge(base: &Checkpoint, top: &mut Checkpoint) -> Result<()> {
+    if base.page_size != top.page_size {
+        return Err(anyhow!(
+            "parent image has a page size of {} but this one has {}",
+            base.page_size,
+            top.page_size
+        ));
+    }
+    let page_size = top.page_size as usize;
+
+    for process in top.processes.iter_mut() {
+        let base_process = base
+            .processes
+            .iter()
+            .find(|p| p.pid == process.pid)
+            .ok_or(anyhow!(
+                "process {} is not in the parent image",
+                process.pid
+            ))?;
+
+        for memory_map in process.memory_maps.iter_mut() {
+            let base_map = base_process.memory_maps.iter().find(|m| {
+                m.base_address == memory_map.base_address
+                    && m.size == memory_map.size
+                    && m.label == memory_map.label
+            });
+            if let Some(base_map) = base_map {
+                (memory_map.runs, memory_map.data) =
+                    myprocfs::overlay_runs(base_map, memory_map, page_size);
+            }
+            // the result is complete, so there is nothing left for them to say
+            memory_map.holes = Vec::new();
+        }
+    }
+    Ok(())
+}
+
+pub fn write_image<W: Write>(
+    writer: &mut W,
+    mut checkpoint: Checkpoint,
+    compression: Compression,
+) -> Result<()> {
+    let data: Vec<Vec<u8>> = checkpoint
+        .processes
+        .iter_mut()
+        .flat_map(|process| process.memory_maps.iter_mut())
+        .map(|memory_map| {
+            let data = std::mem::take(&mut memory_map.data);
+            match compression {
+                Compression::None => data,
+                Compression::Lz4 => compress_chunks(&data),
+            }
+        })
+        .collect();
+    let metadata = serde_json::to_vec(&checkpoint)?;
+
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&compression.to_u32().to_le_bytes())?;
+    writer.write_all(&(metadata.len() as u64).to_le_bytes())?;
+    writer.write_all(&metadata)?;
+
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    for region in data.iter() {
+        writer.write_all(&(region.len() as u64).to_le_bytes())?;
+    }
+    for region in data.iter() {
+        writer.write_all(region)?;
+    }
+
+    Ok(())
+}
+
+pub fn read_image<R: Read>(reader: &mut R) -> Result<Checkpoint> {
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|e| anyhow!("could not read image header: {}", e))?;
+    if magic != MAGIC {
+        // not a binary image, so assume it was written with `freeze --json`
+        let mut json = Cursor::new(magic).chain(reader);
+        let checkpoint = serde_json::from_reader(&mut json)
+            .map_err(|e| anyhow!("not a binary image and could not parse as JSON: {}", e))?;
+        check_runs(&checkpoint)?;
+        check_signals(&checkpoint)?;
+        return Ok(checkpoint);
+    }
+
+    let version = read_u32(reader)?;
+    if version != FORMAT_VERSION {
+        return Err(anyhow!(
+            "unsupported image format version {} (expected {})",
+            version,
+            FORMAT_VERSION
+        ));
+    }
+
+    let compression = Compression::from_u32(read_u32(reader)?)?;
+    let metadata_len = read_u64(reader)?;
+    let metadata = read_bytes(reader, metadata_len)
+        .map_err(|e| anyhow!("could not read image metadata: {}", e))?;
+    let mut checkpoint: Checkpoint = serde_json::from_slice(&metadata)
+        .map_err(|e| anyhow!("could not parse image metadata: {}", e))?;
+
+    let mut memory_maps: Vec<_> = checkpoint
+        .processes
+        .iter_mut()
+        .flat_map(|process| process.memory_maps.iter_mut())
+        .collect();
+    let region_count = read_u64(reader)?;
+    if region_count != memory_maps.len() as u64 {
+        return Err(anyhow!(
+            "image has data for {} region(s) but metadata describes {}",
+            region_count,
+            memory_maps.len()
+        ));
+    }
+
+    let mut data_lengths = Vec::new();
+    for _ in 0..region_count {
+        data_lengths.push(read_u64(reader)?);
+    }
+
+    for (memory_map, len) in memory_maps.iter_mut().zip(data_lengths) {
+        let mut region = reader.take(len);
+        let data = match compression {
+            Compression::None => read_bytes(&mut region, len),
+            Compression::Lz4 => decompress_chunks(&mut region, memory_map.size),
+        };
+        memory_map.data = data.map_err(|e| {
+            anyhow!(
+                "could not read {} byte(s) of data for region at {:#x}: {}",
+                len,
+                memory_map.base_address,
+                e
+            )
+        })?;
+        if region.limit() != 0 {
+            return Err(anyhow!(
+                "data for region at {:#x} has {} byte(s) left over",
+                memory_map.base_address,
+                region.limit()
+            ));
+        }
+    }
+
+    check_runs(&checkpoint)?;
+    check_signals(&checkpoint)?;
+    Ok(checkpoint)
+}
+
+fn check_runs(checkpoint: &Checkpoint) -> Result<()> {
+    for memory_map in checkpoint
+        .processes
+        .iter()
+        .flat_map(|p| p.memory_maps.iter())
+    {
+        memory_map.check_runs(checkpoint.page_size)?;
+    }
+    Ok(())
+}
+
+fn check_signals(checkpoint: &Checkpoint) -> Result<()> {
+    for process in checkpoint.processes.iter() {
+        process.signals.check()?;
+        for signal in process
+            .threads
+            .iter()
+            .flat_map(|t| t.pending_signals.iter())
+        {
+            signal.check()?;
+        }
+    }
+    Ok(())
+}
+
+impl Compression {
+    fn to_u32(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    fn from_u32(x: u32) -> Result<Self> {
+        match x {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            _ => Err(anyhow!("unknown compression type {} in image header", x)),
+        }
+    }
+}
+
+/// splits `data` into `COMPRESSION_CHUNK`-sized pieces and compresses each one separately, each
+/// preceded by its compressed length as a u32, so that reading it back never needs the whole of
+/// the compressed region in memory next to the decompressed one
+fn compress_chunks(data: &[u8]) -> Vec<u8> {
+    let mut r = Vec::new();
+    for chunk in data.chunks(COMPRESSION_CHUNK) {
+        let compressed = lz4_flex::compress_prepend_size(chunk);
+        r.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        r.extend_from_slice(&compressed);
+    }
+    r
+}
+
+/// reads what `compress_chunks` wrote up to the end of `reader`, failing if it comes to more than
+/// `max_len` bytes
+fn decompress_chunks<R: Read>(reader: &mut std::io::Take<R>, max_len: u64) -> Result<Vec<u8>> {
+    let mut r = Vec::new();
+    while reader.limit() > 0 {
+        let compressed_len = read_u32(reader)?;
+        let compressed = read_bytes(reader, compressed_len as u64)?;
+        // decompression allocates whatever size the chunk claims up front
+        let decompressed_len = compressed
+            .get(..4)
+            .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as u64);
+        if decompressed_len > Some(COMPRESSION_CHUNK as u64)
+            || r.len() as u64 + decompressed_len.unwrap_or(0) > max_len
+        {
+            return Err(anyhow!("data is larger than the region"));
+        }
+        r.extend(lz4_flex::decompress_size_prepended(&compressed)?);
+    }
+    Ok(r)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// reads `len` bytes, growing the buffer as they come in rather than trusting `len` with an
+/// allocation up front, as it comes from the file
+fn read_bytes<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>> {
+    let mut r = Vec::new();
+    reader.take(len).read_to_end(&mut r)?;
+    if r.len() as u64 != len {
+        return Err(anyhow!("image ends after {} of {} byte(s)", r.len(), len));
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use super::{
+        load_chain, merge, read_image, save, write_image, Compression, Format, COMPRESSION_CHUNK,
+        MAGIC,
+    };
+    use crate::{
+        common::{
+            arch::{FpRegisters, NamedRegisters, Registers, FP_REGISTERS_SIZE, REGISTER_NAMES},
+            signals::{QueuedSignal, SignalState},
+        },
+        proctool::{
+            attributes::{MemoryLayout, ProcessAttributes, ResourceLimit},
+            cryogenics::{Checkpoint, ProcessState, ThreadState},
+        },
+        teleclient::myprocfs::{MemoryMap, PageRun},
+    };
+
+    pub(crate) fn make_process(pid: i32, ppid: i32) -> ProcessState {
+        let named: NamedRegisters = REGISTER_NAMES
+            .iter()
+            .map(|name| (name.to_string(), 42))
+            .collect();
+        let memory_maps = vec![
+            MemoryMap {
+                base_address: 0x1000,
+                size: 0x2000,
+                label: "[heap]".to_string(),
+                readable: true,
+                writable: true,
+                executable: false,
+                private: true,
+                offset: 0,
+                inode: 0,
+                file: None,
+                runs: vec![PageRun {
+                    offset: 0,
+                    len: 0x2000,
+                }],
+                data: vec![7; 0x2000],
+                holes: Vec::new(),
+            },
+            MemoryMap {
+                base_address: 0x8000,
+                size: 0x1000,
+                label: "[vvar]".to_string(),
+                readable: true,
+                writable: false,
+                executable: false,
+                private: true,
+                offset: 0,
+                inode: 0,
+                file: None,
+                runs: Vec::new(),
+                data: Vec::new(),
+                holes: Vec::new(),
+            },
+        ];
+        ProcessState {
+            pid,
+            ppid,
+            pgid: 100,
+            sid: 1,
+            memory_maps,
+            threads: vec![ThreadState {
+                tid: pid,
+                registers: Registers::try_from(named).unwrap(),
+                fp_registers: FpRegisters::try_from(vec![3; FP_REGISTERS_SIZE]).unwrap(),
+                signal_mask: 1 << 12,
+                robust_list: (0x7000, 24),
+                clear_child_tid: 0x7010,
+                pending_signals: Vec::new(),
+            }],
+            open_files: Vec::new(),
+            signals: SignalState::default(),
+            attributes: ProcessAttributes {
+                cwd: "/".to_string(),
+                umask: 0o022,
+                comm: "test".to_string(),
+                rlimits: vec![ResourceLimit {
+                    resource: 7, // RLIMIT_NOFILE
+                    soft: 1024,
+                    hard: 4096,
+                }],
+            },
+            memory_layout: MemoryLayout {
+                start_code: 0x400000,
+                end_code: 0x401000,
+                start_data: 0x600000,
+                end_data: 0x601000,
+                start_brk: 0x602000,
+                brk: 0x623000,
+                start_stack: 0x7ffc0000,
+                arg_start: 0x7ffc1000,
+                arg_end: 0x7ffc1100,
+                env_start: 0x7ffc1100,
+                env_end: 0x7ffc2000,
+                auxv: vec![0; 16],
+            },
+        }
+    }
+
+    fn make_checkpoint() -> Checkpoint {
+        let mut child = make_process(101, 100);
+        child.memory_maps[0].data = vec![9; 0x2000];
+        let mut checkpoint = Checkpoint::new(None, vec![make_process(100, 1), child]);
+        // whatever the host's, so that the runs above stay aligned
+        checkpoint.page_size = 0x1000;
+        checkpoint
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        for compression in [Compression::None, Compression::Lz4] {
+            let mut buf = Vec::new();
+            write_image(&mut buf, make_checkpoint(), compression).unwrap();
+            assert_eq!(&buf[..MAGIC.len()], &MAGIC[..]);
+
+            let checkpoint = read_image(&mut Cursor::new(buf)).unwrap();
+            assert_eq!(checkpoint.processes.len(), 2);
+            let state = &checkpoint.processes[0];
+            assert_eq!(state.memory_maps.len(), 2);
+            assert_eq!(state.memory_maps[0].label, "[heap]");
+            assert_eq!(state.memory_maps[0].runs.len(), 1);
+            assert_eq!(state.memory_maps[0].data, vec![7; 0x2000]);
+            assert!(state.memory_maps[1].data.is_empty());
+            let thread = &state.threads[0];
+            assert_eq!(thread.registers.pc(), 42);
+            assert_eq!(thread.fp_registers.as_bytes(), &[3; FP_REGISTERS_SIZE][..]);
+            assert_eq!(thread.robust_list, (0x7000, 24));
+            assert_eq!(thread.clear_child_tid, 0x7010);
+
+            let child = &checkpoint.processes[1];
+            assert_eq!(child.ppid, 100);
+            assert_eq!(child.memory_maps[0].data, vec![9; 0x2000]);
+        }
+    }
+
+    #[test]
+    fn test_compression_shrinks_image() {
+        let mut uncompressed = Vec::new();
+        write_image(&mut uncompressed, make_checkpoint(), Compression::None).unwrap();
+        let mut compressed = Vec::new();
+        write_image(&mut compressed, make_checkpoint(), Compression::Lz4).unwrap();
+        assert!(compressed.len() < uncompressed.len());
+    }
+
+    #[test]
+    fn test_compressed_chunks() {
+        // a region spanning several chunks, the last of them partial
+        let mut checkpoint = make_checkpoint();
+        let len = 2 * COMPRESSION_CHUNK + 0x3000;
+        let heap = &mut checkpoint.processes[0].memory_maps[0];
+        heap.size = len as u64;
+        heap.runs = vec![PageRun {
+            offset: 0,
+            len: len as u64,
+        }];
+        heap.data = (0..len).map(|i| (i / 0x1000) as u8).collect();
+        let expected = heap.data.clone();
+
+        let mut buf = Vec::new();
+        write_image(&mut buf, checkpoint, Compression::Lz4).unwrap();
+        let checkpoint = read_image(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(checkpoint.processes[0].memory_maps[0].data, expected);
+
+        // a chunk claiming more than the region holds
+        let mut small = make_checkpoint();
+        small.processes[0].memory_maps[0].size = 0x1000;
+        let mut buf = Vec::new();
+        write_image(&mut buf, small, Compression::Lz4).unwrap();
+        assert!(read_image(&mut Cursor::new(buf)).is_err());
+    }
+
+    #[test]
+    fn test_json_fallback() {
+        let buf = serde_json::to_vec(&make_checkpoint()).unwrap();
+        let checkpoint = read_image(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(checkpoint.processes[0].memory_maps[0].data, vec![7; 0x2000]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let base = make_checkpoint();
+
+        // only the second page of the heap changed
+        let mut top = make_checkpoint();
+        top.parent = Some("base.checkpoint".to_string());
+        let heap = &mut top.processes[0].memory_maps[0];
+        heap.runs = vec![PageRun {
+            offset: 0x1000,
+            len: 0x1000,
+        }];
+        heap.data = vec![8; 0x1000];
+
+        merge(&base, &mut top).unwrap();
+        let heap = &top.processes[0].memory_maps[0];
+        assert_eq!(heap.runs.len(), 1);
+        assert_eq!(&heap.data[..0x1000], &[7; 0x1000][..]);
+        assert_eq!(&heap.data[0x1000..], &[8; 0x1000][..]);
+
+        // the first page was dropped rather than left alone
+        let mut top = make_checkpoint();
+        let heap = &mut top.processes[0].memory_maps[0];
+        heap.runs = Vec::new();
+        heap.data = Vec::new();
+        heap.holes = vec![PageRun {
+            offset: 0,
+            len: 0x1000,
+        }];
+        merge(&base, &mut top).unwrap();
+        let heap = &top.processes[0].memory_maps[0];
+        assert_eq!(heap.data, vec![7; 0x1000]);
+        assert_eq!(heap.runs[0].offset, 0x1000);
+
+        let mut top = make_checkpoint();
+        top.processes[1].pid = 102;
+        assert!(merge(&base, &mut top).is_err());
+
+        let mut top = make_checkpoint();
+        top.page_size = 0x4000;
+        assert!(merge(&base, &mut top).is_err());
+    }
+
+    #[test]
+    fn test_bad_runs() {
+        let mut checkpoint = make_checkpoint();
+        checkpoint.processes[0].memory_maps[0].runs[0].len = 0x3000;
+        let mut buf = Vec::new();
+        write_image(&mut buf, checkpoint, Compression::None).unwrap();
+        assert!(read_image(&mut Cursor::new(buf)).is_err());
+    }
+
+    #[test]
+    fn test_bad_siginfo() {
+        let mut checkpoint = make_checkpoint();
+        checkpoint.processes[0].threads[0]
+            .pending_signals
+            .push(QueuedSignal {
+                siginfo: vec![0; 2],
+            });
+        let mut buf = Vec::new();
+        write_image(&mut buf, checkpoint, Compression::None).unwrap();
+        assert!(read_image(&mut Cursor::new(buf)).is_err());
+    }
+
+    #[test]
+    fn test_huge_lengths() {
+        let mut buf = Vec::new();
+        write_image(&mut buf, make_checkpoint(), Compression::None).unwrap();
+        // claim an exabyte of metadata, which mustn't be allocated before it's found missing
+        let offset = MAGIC.len() + 8;
+        buf[offset..offset + 8].copy_from_slice(&(1u64 << 60).to_le_bytes());
+        assert!(read_image(&mut Cursor::new(buf)).is_err());
+    }
+
+    #[test]
+    fn test_truncated_image() {
+        let mut buf = Vec::new();
+        write_image(&mut buf, make_checkpoint(), Compression::None).unwrap();
+        buf.truncate(buf.len() - 1);
+        assert!(read_image(&mut Cursor::new(buf)).is_err());
+    }
+
+    #[test]
+    fn test_parent_cycle() {
+        let dir = std::env::temp_dir().join(format!("proctool-cycle-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+
+        // an image that is its own parent, and two that are each other's
+        let mut own = make_checkpoint();
+        own.parent = Some("own".to_string());
+        save(&path("own"), own, Format::Binary(Compression::None)).unwrap();
+        assert!(load_chain(&path("own")).is_err());
+
+        for (name, parent) in [("a", "b"), ("b", "a")] {
+            let mut checkpoint = make_checkpoint();
+            checkpoint.parent = Some(parent.to_string());
+            save(&path(name), checkpoint, Format::Binary(Compression::None)).unwrap();
+        }
+        assert!(load_chain(&path("a")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
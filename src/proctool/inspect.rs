@@ -0,0 +1,383 @@
+// Human-readable views of saved checkpoints, for `proctool state show` and `proctool state diff`.
+
+use std::{collections::HashMap, fmt::Write};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    common::arch::{NamedRegisters, REGISTER_NAMES},
+    proctool::cryogenics::{Checkpoint, ProcessState},
+    teleclient::myprocfs::{self, MemoryMap},
+};
+
+pub fn show(checkpoint: &Checkpoint) -> String {
+    let mut r = String::new();
+    match &checkpoint.parent {
+        Some(parent) => writeln!(r, "incremental image on top of {}", parent).unwrap(),
+        None => writeln!(r, "full image").unwrap(),
+    }
+
+    for state in checkpoint.processes.iter() {
+        writeln!(r).unwrap();
+        show_process(&mut r, state);
+    }
+    r
+}
+
+fn show_process(r: &mut String, state: &ProcessState) {
+    let attributes = &state.attributes;
+    writeln!(
+        r,
+        "process {} ({}): parent {}, group {}, session {}",
+        state.pid, attributes.comm, state.ppid, state.pgid, state.sid
+    )
+    .unwrap();
+    writeln!(
+        r,
+        "  cwd {}, umask {:03o}",
+        attributes.cwd, attributes.umask
+    )
+    .unwrap();
+    let layout = &state.memory_layout;
+    writeln!(
+        r,
+        "  brk {:#x} (from {:#x}), stack {:#x}",
+        layout.brk, layout.start_brk, layout.start_stack
+    )
+    .unwrap();
+    writeln!(
+        r,
+        "  {} signal action(s), {} pending signal(s)",
+        state.signals.actions.len(),
+        state.signals.pending.len()
+    )
+    .unwrap();
+
+    for open_file in state.open_files.iter() {
+        writeln!(
+            r,
+            "  fd {}: {} (flags {:#o}, offset {})",
+            open_file.fd, open_file.path, open_file.flags, open_file.offset
+        )
+        .unwrap();
+    }
+
+    for thread in state.threads.iter() {
+        writeln!(
+            r,
+            "  thread {}: signal mask {:#x}, {} pending signal(s)",
+            thread.tid,
+            thread.signal_mask,
+            thread.pending_signals.len()
+        )
+        .unwrap();
+        let registers = NamedRegisters::from(thread.registers);
+        for names in REGISTER_NAMES.chunks(4) {
+            let line: Vec<String> = names
+                .iter()
+                .map(|name| format!("{:>8}={:#018x}", name, registers[*name]))
+                .collect();
+            writeln!(r, "   {}", line.join(" ")).unwrap();
+        }
+    }
+
+    let total: u64 = state.memory_maps.iter().map(|map| map.size).sum();
+    let stored: usize = state.memory_maps.iter().map(|map| map.data.len()).sum();
+    writeln!(
+        r,
+        "  {} memory map(s), {} byte(s) mapped, {} byte(s) stored:",
+        state.memory_maps.len(),
+        total,
+        stored
+    )
+    .unwrap();
+    for map in state.memory_maps.iter() {
+        writeln!(r, "    {}", map).unwrap();
+        write!(
+            r,
+            "      {} byte(s) stored in {} run(s), hash {:#018x}",
+            map.data.len(),
+            map.runs.len(),
+            myprocfs::fnv1a(myprocfs::FNV_OFFSET_BASIS, &map.data)
+        )
+        .unwrap();
+        if let Some(file) = &map.file {
+            write!(r, ", rest from the file (hash {:#018x})", file.hash).unwrap();
+        }
+        writeln!(r).unwrap();
+    }
+}
+
+/// how a region differs between two checkpoints
+#[derive(Debug, PartialEq)]
+pub enum RegionChange {
+    Added(String),
+    Removed(String),
+    /// same start address, but a different size, permissions or label
+    Remapped {
+        before: String,
+        after: String,
+    },
+    /// same mapping, different contents; the offsets of the pages that differ
+    PagesChanged {
+        region: String,
+        pages: Vec<u64>,
+    },
+}
+
+/// the changes to one process; either pid is missing if the process is only in one checkpoint
+#[derive(Debug)]
+pub struct ProcessDiff {
+    pub pid_a: Option<i32>,
+    pub pid_b: Option<i32>,
+    pub changes: Vec<RegionChange>,
+}
+
+/// compares two complete checkpoints (see `image::load_chain`), pairing up processes by pid
+///
+/// a lone process on either side is compared with the other side's even if its pid changed, e.g.
+/// because it was thawed in between; fails if the checkpoints were taken with different page sizes
+pub fn diff(a: &Checkpoint, b: &Checkpoint) -> Result<Vec<ProcessDiff>> {
+    if a.page_size != b.page_size {
+        return Err(anyhow!(
+            "can't compare checkpoints with page sizes of {} and {}",
+            a.page_size,
+            b.page_size
+        ));
+    }
+    let page_size = a.page_size as usize;
+
+    if let ([state_a], [state_b]) = (&a.processes[..], &b.processes[..]) {
+        return Ok(vec![diff_process(state_a, state_b, page_size)]);
+    }
+
+    let mut r = Vec::new();
+    for state_a in a.processes.iter() {
+        match b
+            .processes
+            .iter()
+            .find(|state_b| state_b.pid == state_a.pid)
+        {
+            Some(state_b) => r.push(diff_process(state_a, state_b, page_size)),
+            None => r.push(ProcessDiff {
+                pid_a: Some(state_a.pid),
+                pid_b: None,
+                changes: Vec::new(),
+            }),
+        }
+    }
+    for state_b in b.processes.iter() {
+        if !a.processes.iter().any(|state_a| state_a.pid == state_b.pid) {
+            r.push(ProcessDiff {
+                pid_a: None,
+                pid_b: Some(state_b.pid),
+                changes: Vec::new(),
+            });
+        }
+    }
+    Ok(r)
+}
+
+fn diff_process(a: &ProcessState, b: &ProcessState, page_size: usize) -> ProcessDiff {
+    let mut changes = Vec::new();
+    for map_a in a.memory_maps.iter() {
+        let map_b = match b
+            .memory_maps
+            .iter()
+            .find(|map_b| map_b.base_address == map_a.base_address)
+        {
+            Some(map_b) => map_b,
+            None => {
+                changes.push(RegionChange::Removed(map_a.to_string()));
+                continue;
+            }
+        };
+
+        if map_a.to_string() != map_b.to_string() {
+            changes.push(RegionChange::Remapped {
+                before: map_a.to_string(),
+                after: map_b.to_string(),
+            });
+            continue;
+        }
+
+        let pages = changed_pages(map_a, map_b, page_size);
+        if !pages.is_empty() {
+            changes.push(RegionChange::PagesChanged {
+                region: map_a.to_string(),
+                pages,
+            });
+        }
+    }
+
+    for map_b in b.memory_maps.iter() {
+        if !a
+            .memory_maps
+            .iter()
+            .any(|map_a| map_a.base_address == map_b.base_address)
+        {
+            changes.push(RegionChange::Added(map_b.to_string()));
+        }
+    }
+
+    ProcessDiff {
+        pid_a: Some(a.pid),
+        pid_b: Some(b.pid),
+        changes,
+    }
+}
+
+/// returns the offsets of the pages whose contents differ between two versions of a region
+fn changed_pages(a: &MemoryMap, b: &MemoryMap, page_size: usize) -> Vec<u64> {
+    let pages_a = stored_pages(a, page_size);
+    let pages_b = stored_pages(b, page_size);
+    // pages that weren't stored are zero, or come from the file if the region has one
+    let same_file = a.file.as_ref().map(|f| f.hash) == b.file.as_ref().map(|f| f.hash);
+
+    (0..a.size)
+        .step_by(page_size)
+        .filter(|offset| match (pages_a.get(offset), pages_b.get(offset)) {
+            (None, None) => !same_file,
+            (page_a, page_b) => page_a != page_b,
+        })
+        .collect()
+}
+
+/// maps page offsets to their stored contents
+fn stored_pages(memory_map: &MemoryMap, page_size: usize) -> HashMap<u64, &[u8]> {
+    let mut r = HashMap::new();
+    for (addr, bytes) in memory_map.populated_runs() {
+        for (i, page) in bytes.chunks(page_size).enumerate() {
+            let offset = addr - memory_map.base_address + (i * page_size) as u64;
+            r.insert(offset, page);
+        }
+    }
+    r
+}
+
+pub fn show_diff(diffs: &[ProcessDiff]) -> String {
+    let mut r = String::new();
+    for diff in diffs {
+        match (diff.pid_a, diff.pid_b) {
+            (Some(pid), None) => writeln!(r, "process {} is only in the first image", pid),
+            (None, Some(pid)) => writeln!(r, "process {} is only in the second image", pid),
+            (Some(a), Some(b)) if a != b => writeln!(r, "process {} (now {}):", a, b),
+            (Some(pid), _) => writeln!(r, "process {}:", pid),
+            (None, None) => continue,
+        }
+        .unwrap();
+
+        if diff.pid_a.is_some() && diff.pid_b.is_some() && diff.changes.is_empty() {
+            writeln!(r, "  no changes").unwrap();
+        }
+        for change in diff.changes.iter() {
+            match change {
+                RegionChange::Added(region) => writeln!(r, "  + {}", region),
+                RegionChange::Removed(region) => writeln!(r, "  - {}", region),
+                RegionChange::Remapped { before, after } => {
+                    writeln!(r, "  - {}\n  + {}", before, after)
+                }
+                RegionChange::PagesChanged { region, pages } => {
+                    let offsets: Vec<String> = pages
+                        .iter()
+                        .map(|offset| format!("{:#x}", offset))
+                        .collect();
+                    writeln!(
+                        r,
+                        "  ~ {}\n    {} page(s) changed at offset(s) {}",
+                        region,
+                        pages.len(),
+                        offsets.join(", ")
+                    )
+                }
+            }
+            .unwrap();
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, show, RegionChange};
+    use crate::{
+        proctool::{cryogenics::Checkpoint, image::tests::make_process},
+        teleclient::myprocfs::PageRun,
+    };
+
+    fn checkpoint(pid: i32) -> Checkpoint {
+        let mut checkpoint = Checkpoint::new(None, vec![make_process(pid, 1)]);
+        checkpoint.page_size = 0x1000;
+        checkpoint
+    }
+
+    #[test]
+    fn test_diff_identical() {
+        let diffs = diff(&checkpoint(100), &checkpoint(100)).unwrap();
+        assert_eq!(diffs.len(), 1);
+        assert!(diffs[0].changes.is_empty());
+        let mut other = checkpoint(100);
+        other.page_size = 0x4000;
+        assert!(diff(&checkpoint(100), &other).is_err());
+    }
+
+    #[test]
+    fn test_diff_changed_pages() {
+        let a = checkpoint(100);
+        let mut b = checkpoint(200);
+        b.processes[0].memory_maps[0].data[0x1000] = 8;
+        b.processes[0].memory_maps.remove(1);
+
+        let diffs = diff(&a, &b).unwrap();
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].pid_b, Some(200));
+        let changes = &diffs[0].changes;
+        assert_eq!(changes.len(), 2);
+        assert!(matches!(
+            &changes[0],
+            RegionChange::PagesChanged { pages, .. } if pages == &vec![0x1000]
+        ));
+        assert!(matches!(&changes[1], RegionChange::Removed(region) if region.contains("[vvar]")));
+    }
+
+    #[test]
+    fn test_diff_unstored_page() {
+        // a page that's gone from `runs` is now all zero
+        let a = checkpoint(100);
+        let mut b = checkpoint(100);
+        let heap = &mut b.processes[0].memory_maps[0];
+        heap.runs = vec![PageRun {
+            offset: 0x1000,
+            len: 0x1000,
+        }];
+        heap.data = vec![7; 0x1000];
+
+        let diffs = diff(&a, &b).unwrap();
+        assert_eq!(
+            diffs[0].changes,
+            vec![RegionChange::PagesChanged {
+                region: a.processes[0].memory_maps[0].to_string(),
+                pages: vec![0],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_unpaired_processes() {
+        let a = Checkpoint::new(None, vec![make_process(100, 1), make_process(101, 100)]);
+        let b = Checkpoint::new(None, vec![make_process(100, 1), make_process(102, 100)]);
+
+        let diffs = diff(&a, &b).unwrap();
+        assert_eq!(diffs.len(), 3);
+        assert_eq!((diffs[1].pid_a, diffs[1].pid_b), (Some(101), None));
+        assert_eq!((diffs[2].pid_a, diffs[2].pid_b), (None, Some(102)));
+    }
+
+    #[test]
+    fn test_show() {
+        let s = show(&checkpoint(100));
+        assert!(s.contains("process 100 (test)"));
+        assert!(s.contains("[heap]"));
+        assert!(s.contains("8192 byte(s) stored in 1 run(s)"));
+    }
+}
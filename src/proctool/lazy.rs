@@ -0,0 +1,555 @@
+// Lazy thaw: the process is started before most of its memory is back. Its anonymous regions are
+// mapped empty and registered with userfaultfd, and a page server thread in proctool fills each
+// page in from the image the first time it is touched, fetching the rest in the background
+// whenever nothing is waiting. Once every stored page is in, the userfaultfd is closed and the
+// regions go back to being ordinary memory. Until then the server also follows the process: a
+// forked child gets a server of its own, and pages that are dropped or moved are forgotten or
+// moved along with them.
+
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use anyhow::{anyhow, Result};
+use nix::unistd;
+
+use crate::{
+    proctool::{cryogenics::ProcessState, pcontroller},
+    teleclient::myprocfs::MemoryMap,
+};
+
+// from <linux/userfaultfd.h>, not exported by libc
+const UFFD_API: u64 = 0xaa;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+const UFFD_FEATURE_EVENT_FORK: u64 = 1 << 1;
+const UFFD_FEATURE_EVENT_REMAP: u64 = 1 << 2;
+const UFFD_FEATURE_EVENT_REMOVE: u64 = 1 << 3;
+const UFFD_FEATURE_EVENT_UNMAP: u64 = 1 << 6;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFD_EVENT_FORK: u8 = 0x13;
+const UFFD_EVENT_REMAP: u8 = 0x14;
+const UFFD_EVENT_REMOVE: u8 = 0x15;
+const UFFD_EVENT_UNMAP: u8 = 0x16;
+const UFFD_MSG_SIZE: usize = 32;
+
+// _IOWR(0xaa, nr, struct) and _IOR(0xaa, nr, struct)
+const UFFDIO_API: libc::c_ulong = 0xc018aa3f;
+const UFFDIO_REGISTER: libc::c_ulong = 0xc020aa00;
+const UFFDIO_WAKE: libc::c_ulong = 0x8010aa02;
+const UFFDIO_COPY: libc::c_ulong = 0xc028aa03;
+const UFFDIO_ZEROPAGE: libc::c_ulong = 0xc020aa04;
+
+/// how much the background fetch copies at a time, in pages, between checks for faults
+const PREFETCH_PAGES: usize = 64;
+
+/// how long to wait for an event when the background fetch can't get anywhere without one
+const STALLED_POLL_MS: i32 = 100;
+
+#[repr(C)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+#[repr(C)]
+struct UffdioZeropage {
+    range: UffdioRange,
+    mode: u64,
+    zeropage: i64,
+}
+
+/// whether `map` can be left for the page server: only anonymous memory can be registered, and
+/// the stacks the threads are running on would be needed straight away anyway
+pub fn is_lazy(map: &MemoryMap, state: &ProcessState) -> bool {
+    let end = map.base_address + map.size;
+    map.file.is_none()
+        && map.readable
+        && !map.runs.is_empty()
+        && !state
+            .threads
+            .iter()
+            .any(|thread| (map.base_address..end).contains(&thread.registers.sp()))
+}
+
+/// fills in the missing pages of one process
+pub struct PageServer<'a> {
+    pid: unistd::Pid,
+    uffd: OwnedFd,
+    /// to notice the process exiting; a forked child's pid isn't known, so it has none
+    pidfd: Option<OwnedFd>,
+    /// the stored pages of every registered region that haven't been fetched yet, by address
+    runs: Vec<(u64, &'a [u8])>,
+    /// servers for children forked since, still to be started
+    forked: Vec<PageServer<'a>>,
+    page_size: u64,
+}
+
+impl<'a> PageServer<'a> {
+    /// takes over the pages of `memory_maps`, which have to have been mapped with
+    /// `map_region_without_contents` and left untouched since
+    pub fn register(
+        pid: unistd::Pid,
+        uffd: OwnedFd,
+        memory_maps: &[&'a MemoryMap],
+        page_size: u64,
+    ) -> Result<Self> {
+        // without these, a forked child would find the pages missing with no one to serve them,
+        // and pages the process dropped or moved would be filled in with stale contents
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            features: UFFD_FEATURE_EVENT_FORK
+                | UFFD_FEATURE_EVENT_REMAP
+                | UFFD_FEATURE_EVENT_REMOVE
+                | UFFD_FEATURE_EVENT_UNMAP,
+            ioctls: 0,
+        };
+        ioctl(&uffd, UFFDIO_API, &mut api).map_err(|e| {
+            anyhow!(
+                "UFFDIO_API failed: {} (following forks needs CAP_SYS_PTRACE and Linux 4.11)",
+                e
+            )
+        })?;
+
+        let mut runs = Vec::new();
+        for map in memory_maps {
+            let mut register = UffdioRegister {
+                range: UffdioRange {
+                    start: map.base_address,
+                    len: map.size,
+                },
+                mode: UFFDIO_REGISTER_MODE_MISSING,
+                ioctls: 0,
+            };
+            ioctl(&uffd, UFFDIO_REGISTER, &mut register)
+                .map_err(|e| anyhow!("UFFDIO_REGISTER failed at {:#x}: {}", map.base_address, e))?;
+            runs.extend(map.populated_runs());
+        }
+        runs.sort_by_key(|(addr, _)| *addr);
+
+        Ok(Self {
+            pid,
+            uffd,
+            pidfd: Some(pcontroller::pidfd_open(pid)?),
+            runs,
+            forked: Vec::new(),
+            page_size,
+        })
+    }
+
+    /// serves pages until every one of them is in or the process is gone, and does the same for
+    /// every child it forks in the meantime
+    pub fn run(mut self) -> Result<()> {
+        std::thread::scope(|scope| {
+            let start_forked = |forked: &mut Vec<PageServer<'a>>| {
+                for child in forked.drain(..) {
+                    scope.spawn(move || {
+                        if let Err(e) = child.run() {
+                            println!("error: {}", e);
+                        }
+                    });
+                }
+            };
+
+            let mut timeout = 0;
+            let result = loop {
+                start_forked(&mut self.forked);
+                if self.runs.is_empty() {
+                    break Ok(());
+                }
+                match self.serve_once(timeout) {
+                    Ok(Some(progress)) => timeout = if progress { 0 } else { STALLED_POLL_MS },
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                }
+            };
+            // a child forked on the way out still needs its pages, even if this process is done
+            start_forked(&mut self.forked);
+            // dropping the userfaultfd unregisters everything, and any page that is still
+            // missing was never stored, so it is zero
+            result
+        })
+    }
+
+    /// waits up to `timeout` milliseconds for faults, then serves them or fetches more in the
+    /// background; returns whether that got anywhere, or None once the process is gone
+    fn serve_once(&mut self, timeout: i32) -> Result<Option<bool>> {
+        let mut fds = vec![libc::pollfd {
+            fd: self.uffd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        if let Some(pidfd) = &self.pidfd {
+            fds.push(libc::pollfd {
+                fd: pidfd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            });
+        }
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+            return Err(anyhow!("poll failed: {}", io::Error::last_os_error()));
+        }
+
+        if fds.get(1).is_some_and(|fd| fd.revents != 0) {
+            return Ok(None);
+        }
+        let served = if fds[0].revents != 0 {
+            self.serve_faults().map(|_| true)
+        } else {
+            self.prefetch()
+        };
+        match served {
+            Err(e) if e.raw_os_error() == Some(libc::ESRCH) => Ok(None),
+            Err(e) => Err(anyhow!("failed to serve pages of {}: {}", self.pid, e)),
+            Ok(progress) => Ok(Some(progress)),
+        }
+    }
+
+    /// answers the faults and handles the events that are waiting
+    fn serve_faults(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; UFFD_MSG_SIZE * 16];
+        let n = unsafe {
+            libc::read(
+                self.uffd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EAGAIN) => Ok(()),
+                _ => Err(e),
+            };
+        }
+
+        for msg in buf[..n as usize].chunks_exact(UFFD_MSG_SIZE) {
+            let u64_at =
+                |offset: usize| u64::from_ne_bytes(msg[offset..offset + 8].try_into().unwrap());
+            match msg[0] {
+                UFFD_EVENT_PAGEFAULT => self.serve_page(u64_at(16) & !(self.page_size - 1))?,
+                // the child's userfaultfd, now open in this process; what it is missing is
+                // exactly what hasn't been fetched here yet
+                UFFD_EVENT_FORK => {
+                    let fd = u32::from_ne_bytes(msg[8..12].try_into().unwrap());
+                    self.forked.push(PageServer {
+                        pid: self.pid,
+                        uffd: unsafe { OwnedFd::from_raw_fd(fd as i32) },
+                        pidfd: None,
+                        runs: self.runs.clone(),
+                        forked: Vec::new(),
+                        page_size: self.page_size,
+                    });
+                }
+                UFFD_EVENT_REMAP => {
+                    let (from, to, len) = (u64_at(8), u64_at(16), u64_at(24));
+                    self.move_pages(from, from + len, |addr| Some(to + (addr - from)));
+                }
+                // dropped pages read as zero from now on, not as what was saved
+                UFFD_EVENT_REMOVE | UFFD_EVENT_UNMAP => {
+                    self.move_pages(u64_at(8), u64_at(16), |_| None)
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// moves the stored pages in [start, end) to the address `f` gives for the first of them, or
+    /// forgets them if it gives None
+    fn move_pages<F: Fn(u64) -> Option<u64>>(&mut self, start: u64, end: u64, f: F) {
+        let mut runs = Vec::new();
+        for (addr, bytes) in self.runs.drain(..) {
+            let run_end = addr + bytes.len() as u64;
+            let (from, to) = (addr.max(start), run_end.min(end));
+            if from >= to {
+                runs.push((addr, bytes));
+                continue;
+            }
+
+            let part = |a: u64, b: u64| &bytes[(a - addr) as usize..(b - addr) as usize];
+            if addr < from {
+                runs.push((addr, part(addr, from)));
+            }
+            if let Some(new_addr) = f(from) {
+                runs.push((new_addr, part(from, to)));
+            }
+            if to < run_end {
+                runs.push((to, part(to, run_end)));
+            }
+        }
+        runs.sort_by_key(|(addr, _)| *addr);
+        self.runs = runs;
+    }
+
+    fn serve_page(&self, page: u64) -> io::Result<()> {
+        let i = self
+            .runs
+            .partition_point(|(addr, bytes)| addr + bytes.len() as u64 <= page);
+        let r = match self.runs.get(i) {
+            Some((addr, bytes)) if *addr <= page => {
+                let offset = (page - addr) as usize;
+                self.copy(page, &bytes[offset..offset + self.page_size as usize])
+                    .map(|_| ())
+            }
+            _ => self.zeropage(page),
+        };
+
+        match r {
+            // the background fetch got there first, but the faulting thread may not have been
+            // woken yet
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => self.wake(page),
+            // the process unmapped the region in the meantime
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            r => r,
+        }
+    }
+
+    /// copies the next few pages over whether or not they have been touched; returns false if
+    /// none could be, because the kernel wants an event read first
+    fn prefetch(&mut self) -> io::Result<bool> {
+        let (addr, bytes) = self.runs[0];
+        let len = bytes.len().min(PREFETCH_PAGES * self.page_size as usize);
+
+        let advance = match self.copy(addr, &bytes[..len]) {
+            Ok(copied) => copied,
+            // a fault has already brought in the page
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => self.page_size as usize,
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => bytes.len(),
+            // the process is forking or remapping, and the event for it is still to come
+            Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        if advance >= bytes.len() {
+            self.runs.remove(0);
+        } else {
+            self.runs[0] = (addr + advance as u64, &bytes[advance..]);
+        }
+        Ok(true)
+    }
+
+    /// returns how many bytes were copied, which is less than asked for if one of the pages was
+    /// already there
+    fn copy(&self, dst: u64, src: &[u8]) -> io::Result<usize> {
+        let mut copy = UffdioCopy {
+            dst,
+            src: src.as_ptr() as u64,
+            len: src.len() as u64,
+            mode: 0,
+            copy: 0,
+        };
+        match ioctl(&self.uffd, UFFDIO_COPY, &mut copy) {
+            Err(e) if e.raw_os_error() == Some(libc::EAGAIN) && copy.copy > 0 => {
+                Ok(copy.copy as usize)
+            }
+            Err(e) => Err(e),
+            Ok(()) => Ok(src.len()),
+        }
+    }
+
+    fn zeropage(&self, page: u64) -> io::Result<()> {
+        let mut zeropage = UffdioZeropage {
+            range: UffdioRange {
+                start: page,
+                len: self.page_size,
+            },
+            mode: 0,
+            zeropage: 0,
+        };
+        ioctl(&self.uffd, UFFDIO_ZEROPAGE, &mut zeropage)
+    }
+
+    fn wake(&self, page: u64) -> io::Result<()> {
+        let mut range = UffdioRange {
+            start: page,
+            len: self.page_size,
+        };
+        ioctl(&self.uffd, UFFDIO_WAKE, &mut range)
+    }
+}
+
+fn ioctl<T>(fd: &OwnedFd, request: libc::c_ulong, arg: &mut T) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd.as_raw_fd(), request, arg as *mut T) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::teleclient::myprocfs::PageRun;
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    /// a region of our own memory, mapped empty, that is supposed to hold `data`
+    fn empty_region(data: Vec<u8>) -> MemoryMap {
+        let size = 4 * PAGE_SIZE;
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        MemoryMap {
+            base_address: addr as u64,
+            size,
+            label: String::new(),
+            readable: true,
+            writable: true,
+            executable: false,
+            private: true,
+            offset: 0,
+            inode: 0,
+            file: None,
+            // the first and third pages
+            runs: vec![
+                PageRun {
+                    offset: 0,
+                    len: PAGE_SIZE,
+                },
+                PageRun {
+                    offset: 2 * PAGE_SIZE,
+                    len: PAGE_SIZE,
+                },
+            ],
+            data,
+            holes: Vec::new(),
+        }
+    }
+
+    fn own_userfaultfd() -> OwnedFd {
+        let fd =
+            unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        assert!(fd >= 0, "userfaultfd: {}", io::Error::last_os_error());
+        unsafe { OwnedFd::from_raw_fd(fd as i32) }
+    }
+
+    /// userfaultfd(2) needs vm.unprivileged_userfaultfd or CAP_SYS_PTRACE, and following forks
+    /// needs the capability either way
+    fn userfaultfd_allowed() -> bool {
+        let fd =
+            unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        if fd < 0 {
+            eprintln!("skipping: userfaultfd: {}", io::Error::last_os_error());
+            return false;
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            features: UFFD_FEATURE_EVENT_FORK,
+            ioctls: 0,
+        };
+        if let Err(e) = ioctl(&fd, UFFDIO_API, &mut api) {
+            eprintln!("skipping: UFFDIO_API: {}", e);
+            return false;
+        }
+        true
+    }
+
+    fn read_byte(addr: u64) -> u8 {
+        unsafe { std::ptr::read_volatile(addr as *const u8) }
+    }
+
+    /// serves faults and events until `thread` is done; other tests may fork meanwhile, so more
+    /// than the one message can turn up
+    fn serve_until<T>(server: &mut PageServer, thread: std::thread::JoinHandle<T>) -> T {
+        let mut pollfd = libc::pollfd {
+            fd: server.uffd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        while !thread.is_finished() {
+            if unsafe { libc::poll(&mut pollfd, 1, 100) } == 1 {
+                server.serve_faults().unwrap();
+            }
+        }
+        thread.join().unwrap()
+    }
+
+    #[test]
+    fn test_serve_fault() {
+        if !userfaultfd_allowed() {
+            return;
+        }
+        let mut data = vec![1; PAGE_SIZE as usize];
+        data.extend(vec![3; PAGE_SIZE as usize]);
+        let map = empty_region(data);
+        let base = map.base_address;
+        let mut server =
+            PageServer::register(unistd::getpid(), own_userfaultfd(), &[&map], PAGE_SIZE).unwrap();
+
+        // the reader blocks until the fault is served
+        let reader = std::thread::spawn(move || read_byte(base + 2 * PAGE_SIZE + 5));
+        assert_eq!(serve_until(&mut server, reader), 3);
+
+        // a page that was never stored comes back as zeros
+        let reader = std::thread::spawn(move || read_byte(base + PAGE_SIZE));
+        assert_eq!(serve_until(&mut server, reader), 0);
+
+        // the rest is fetched in the background, skipping the page that is already in
+        server.run().unwrap();
+        assert_eq!(read_byte(base), 1);
+        assert_eq!(read_byte(base + 3 * PAGE_SIZE), 0);
+        unsafe { libc::munmap(base as *mut libc::c_void, map.size as usize) };
+    }
+
+    #[test]
+    fn test_dropped_pages() {
+        if !userfaultfd_allowed() {
+            return;
+        }
+        let mut data = vec![1; PAGE_SIZE as usize];
+        data.extend(vec![3; PAGE_SIZE as usize]);
+        let map = empty_region(data);
+        let base = map.base_address;
+        let mut server =
+            PageServer::register(unistd::getpid(), own_userfaultfd(), &[&map], PAGE_SIZE).unwrap();
+
+        // madvise() waits for the event to be read
+        let dropper = std::thread::spawn(move || unsafe {
+            libc::madvise(
+                base as *mut libc::c_void,
+                PAGE_SIZE as usize,
+                libc::MADV_DONTNEED,
+            )
+        });
+        assert_eq!(serve_until(&mut server, dropper), 0);
+        assert_eq!(server.runs.len(), 1);
+
+        // the dropped page isn't brought back
+        server.run().unwrap();
+        assert_eq!(read_byte(base), 0);
+        assert_eq!(read_byte(base + 2 * PAGE_SIZE), 3);
+        unsafe { libc::munmap(base as *mut libc::c_void, map.size as usize) };
+    }
+}
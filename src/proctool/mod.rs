@@ -1,6 +1,14 @@
+pub mod attributes;
+pub mod compat;
+pub mod coredump;
 pub mod cryogenics;
+pub mod fds;
+pub mod image;
+pub mod inspect;
+pub mod lazy;
 pub mod pcontroller;
 pub mod procinfo;
+pub mod store;
 pub mod terminals;
 
 pub mod common {
@@ -23,7 +31,11 @@ pub mod common {
         DaemonRestart,
         DaemonStart,
         DaemonStatus,
+        Autocheckpoint(AutocheckpointArgs),
+        Checkpoint(CheckpointArgs),
+        Checkpoints(CheckpointsArgs),
         Freeze(FreezeArgs),
+        Gcore(GcoreArgs),
         Groups,
         Oblivion(OblivionArgs),
         Pause(PauseArgs),
@@ -35,6 +47,7 @@ pub mod common {
         ColorizeStderr(ColorizeStderrArgs),
         Sessions,
         Spawn(SpawnArgs),
+        State(StateArgs),
         Takeover(TakeoverArgs),
         Terminals,
         TerminalSizes,
@@ -44,9 +57,122 @@ pub mod common {
         WriteStdin(WriteStdinArgs),
     }
 
+    #[derive(clap::Args, Debug, Serialize, Deserialize)]
+    pub struct AutocheckpointArgs {
+        pub pid: i32,
+        /// how long to wait between checkpoints, e.g. 90s, 10m or 2h
+        #[arg(long, value_parser = parse_interval)]
+        pub every: u64,
+        /// how many checkpoints to keep in the store; older ones are removed
+        #[arg(
+            long,
+            default_value_t = 5,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        pub keep: usize,
+        /// compress page data with LZ4
+        #[arg(long)]
+        pub compress: bool,
+    }
+
+    /// parses a number of seconds, minutes, hours or days, such as 10m
+    fn parse_interval(s: &str) -> Result<u64, String> {
+        let (n, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+        let n: u64 = n
+            .parse()
+            .map_err(|_| format!("{} is not a number of seconds", s))?;
+        let scale = match unit {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 60 * 60 * 24,
+            _ => return Err(format!("unknown unit {}; use s, m, h or d", unit)),
+        };
+        n.checked_mul(scale)
+            .filter(|seconds| *seconds > 0)
+            .ok_or(format!("{} is not a usable interval", s))
+    }
+
+    #[derive(clap::Args, Debug, Serialize, Deserialize)]
+    pub struct CheckpointArgs {
+        pub pid: i32,
+        /// only save the pages written to since the previous checkpoint of `pid`
+        #[arg(long)]
+        pub incremental: bool,
+        /// compress page data with LZ4
+        #[arg(long)]
+        pub compress: bool,
+        /// what to call the checkpoint in the store; defaults to <pid>.<n>
+        #[arg(long)]
+        pub name: Option<String>,
+    }
+
+    #[derive(clap::Args, Debug, Serialize, Deserialize)]
+    pub struct CheckpointsArgs {
+        #[command(subcommand)]
+        pub command: CheckpointsCommand,
+    }
+
+    /// manage the checkpoint store, which is in $PROCTOOL_STORE or $PROCTOOL_ROOT/checkpoints
+    #[derive(clap::Subcommand, Debug, Serialize, Deserialize)]
+    pub enum CheckpointsCommand {
+        /// list every checkpoint in the store
+        List,
+        /// remove checkpoints by name
+        Rm(CheckpointsRmArgs),
+        /// remove all but the most recent checkpoints of each process
+        Prune(CheckpointsPruneArgs),
+    }
+
+    #[derive(clap::Args, Debug, Serialize, Deserialize)]
+    pub struct CheckpointsRmArgs {
+        #[arg(required = true)]
+        pub names: Vec<String>,
+    }
+
+    #[derive(clap::Args, Debug, Serialize, Deserialize)]
+    pub struct CheckpointsPruneArgs {
+        /// how many checkpoints of each process to keep, besides the ones they build on
+        #[arg(long)]
+        pub keep: usize,
+    }
+
     #[derive(clap::Args, Debug, Serialize, Deserialize)]
     pub struct FreezeArgs {
         pub pid: i32,
+        /// write the state as JSON instead of the binary image format, for debugging
+        #[arg(long)]
+        pub json: bool,
+        /// compress page data with LZ4
+        #[arg(long, conflicts_with = "json")]
+        pub compress: bool,
+        /// also freeze every descendant of `pid`
+        #[arg(long)]
+        pub tree: bool,
+        /// freeze every process in the process group `pid`
+        #[arg(long, conflicts_with = "tree")]
+        pub pgid: bool,
+        /// let the processes carry on running once they are saved; without this, `--kill` or
+        /// `--takeover`, they are left stopped
+        #[arg(long, group = "afterwards")]
+        pub keep_running: bool,
+        /// kill the processes once they are saved
+        #[arg(long, group = "afterwards")]
+        pub kill: bool,
+        /// replace the processes with `risen` once they are saved
+        #[arg(long, group = "afterwards")]
+        pub takeover: bool,
+        /// what to call the checkpoint in the store; defaults to <pid>
+        #[arg(long)]
+        pub name: Option<String>,
+    }
+
+    #[derive(clap::Args, Debug, Serialize, Deserialize)]
+    pub struct GcoreArgs {
+        pub pid: i32,
+        /// where to write the core file; defaults to <pid>.core
+        #[arg(long, short)]
+        pub output: Option<String>,
     }
 
     #[derive(clap::Args, Debug, Serialize, Deserialize)]
@@ -101,6 +227,42 @@ pub mod common {
         pub uid: Option<u32>,
     }
 
+    #[derive(clap::Args, Debug, Serialize, Deserialize)]
+    pub struct StateArgs {
+        #[command(subcommand)]
+        pub command: StateCommand,
+    }
+
+    #[derive(clap::Subcommand, Debug, Serialize, Deserialize)]
+    pub enum StateCommand {
+        /// print what a saved image holds
+        Show(StateShowArgs),
+        /// print which regions and pages differ between two images of the same process
+        Diff(StateDiffArgs),
+        /// write each process in an image as an ELF core file, to open in gdb
+        ToCore(StateToCoreArgs),
+    }
+
+    #[derive(clap::Args, Debug, Serialize, Deserialize)]
+    pub struct StateShowArgs {
+        pub path: String,
+    }
+
+    #[derive(clap::Args, Debug, Serialize, Deserialize)]
+    pub struct StateDiffArgs {
+        pub a: String,
+        pub b: String,
+    }
+
+    #[derive(clap::Args, Debug, Serialize, Deserialize)]
+    pub struct StateToCoreArgs {
+        pub path: String,
+        /// where to write the core file if the image holds a single process; defaults to
+        /// <pid>.core
+        #[arg(long, short)]
+        pub output: Option<String>,
+    }
+
     #[derive(clap::Args, Debug, Serialize, Deserialize)]
     pub struct TakeoverArgs {
         pub pid: i32,
@@ -113,7 +275,25 @@ pub mod common {
 
     #[derive(clap::Args, Debug, Serialize, Deserialize)]
     pub struct ThawArgs {
-        pub path: String,
+        #[arg(required_unless_present = "name")]
+        pub path: Option<String>,
+        /// thaw a checkpoint from the store instead of an image file
+        #[arg(long, conflicts_with = "path")]
+        pub name: Option<String>,
+        /// give the processes their original pids back, failing if any of them are taken
+        #[arg(long)]
+        pub same_pid: bool,
+        /// thaw into a new pid namespace, where the original pids are always free (implies
+        /// --same-pid)
+        #[arg(long)]
+        pub new_pid_namespace: bool,
+        /// start the processes before their memory is restored, filling pages in as they are
+        /// touched
+        #[arg(long)]
+        pub lazy: bool,
+        /// make sure the image can be restored on this machine first, and refuse to thaw it if not
+        #[arg(long)]
+        pub check: bool,
     }
 
     #[derive(clap::Args, Debug, Serialize, Deserialize)]
@@ -122,4 +302,19 @@ pub mod common {
         #[arg(long)]
         pub message: String,
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse_interval() {
+            assert_eq!(parse_interval("90"), Ok(90));
+            assert_eq!(parse_interval("10m"), Ok(600));
+            assert_eq!(parse_interval("2h"), Ok(7200));
+            assert!(parse_interval("0s").is_err());
+            assert!(parse_interval("10y").is_err());
+            assert!(parse_interval("m").is_err());
+        }
+    }
 }
@@ -1,6 +1,8 @@
 use std::{
-    cell::OnceCell,
+    cell::{Cell, OnceCell},
     io::{IoSlice, IoSliceMut},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
 };
 
 use anyhow::{anyhow, Result};
@@ -8,13 +10,34 @@ use nix::{sys, unistd};
 use syscalls::Sysno;
 
 use crate::{
-    proctool::terminals,
-    teleclient::myprocfs::{self, MemoryMap},
+    common::{
+        arch::{self, FpRegisters, Registers},
+        restorer,
+        signals::{self, QueuedSignal, SignalAction, SIGACTION_SIZE, SIGINFO_SIZE, SIGSET_SIZE},
+        vdso,
+    },
+    proctool::{
+        attributes::{MemoryLayout, ResourceLimit, PRCTL_MM_MAP_SIZE},
+        fds::OpenFile,
+        terminals,
+    },
+    teleclient::myprocfs::{self, MemoryMap, PageRun},
 };
 
+// from <linux/ptrace.h>, not exported by libc
+const PTRACE_PEEKSIGINFO_SHARED: u32 = 1;
+
+pub const SVC_REGION_SIZE: u64 = 4096;
+// the svc region is only ever executed from its first instruction, so the rest of it doubles as
+// scratch space for syscall arguments that have to live in the tracee's memory
+const SVC_REGION_SCRATCH_OFFSET: u64 = 2048;
+
 pub struct ProcessController {
     pid: unistd::Pid,
     memory_maps: OnceCell<Vec<MemoryMap>>,
+    svc_instruction: Cell<Option<u64>>,
+    /// the page `inject_svc_page` mapped, if it had to, until `remove_svc_page` takes it away
+    svc_page: Cell<Option<u64>>,
     pub detach_on_drop: bool,
 }
 
@@ -23,23 +46,53 @@ impl ProcessController {
         Self {
             pid,
             memory_maps: OnceCell::new(),
+            svc_instruction: Cell::new(None),
+            svc_page: Cell::new(None),
             detach_on_drop: true,
         }
     }
 
+    pub fn pid(&self) -> unistd::Pid {
+        self.pid
+    }
+
     pub fn attach(&self) -> Result<()> {
         sys::ptrace::attach(self.pid).map_err(|e| anyhow!("PTRACE_ATTACH failed: {}", e))?;
-        sys::wait::waitpid(self.pid, Some(sys::wait::WaitPidFlag::WSTOPPED))
+        let status = sys::wait::waitpid(self.pid, Some(sys::wait::WaitPidFlag::WSTOPPED))
             .map_err(|e| anyhow!("failed to waitpid after PTRACE_ATTACH: {}", e))?;
+        self.requeue_delivered_signal(status)
+    }
+
+    /// like `attach`, but stops the thread with PTRACE_INTERRUPT instead of a SIGSTOP that its
+    /// parent or shell would notice; detaching lets it carry on
+    pub fn seize(&self) -> Result<()> {
+        sys::ptrace::seize(self.pid, sys::ptrace::Options::empty())
+            .map_err(|e| anyhow!("PTRACE_SEIZE failed: {}", e))?;
+        sys::ptrace::interrupt(self.pid).map_err(|e| anyhow!("PTRACE_INTERRUPT failed: {}", e))?;
+        let status = sys::wait::waitpid(self.pid, Some(sys::wait::WaitPidFlag::WSTOPPED))
+            .map_err(|e| anyhow!("failed to waitpid after PTRACE_INTERRUPT: {}", e))?;
+        self.requeue_delivered_signal(status)
+    }
+
+    /// a signal that was already pending can be what stops the thread first, already taken off
+    /// the queue, and resuming without it would drop it; this puts it back so that it is seen and
+    /// kept pending
+    fn requeue_delivered_signal(&self, status: sys::wait::WaitStatus) -> Result<()> {
+        if let sys::wait::WaitStatus::Stopped(_, signal) = status {
+            if signal != sys::signal::SIGSTOP && sys::ptrace::getsiginfo(self.pid).is_ok() {
+                unsafe { syscalls::syscall!(Sysno::tkill, self.pid.as_raw(), signal as i32) }
+                    .map_err(|e| anyhow!("could not requeue {}: {}", signal, e))?;
+            }
+        }
         Ok(())
     }
 
     pub fn in_syscall(&self) -> Result<bool> {
         let initial_registers = self.get_registers()?;
-        let initial_pc = initial_registers.pc;
+        let initial_pc = initial_registers.pc();
         self.step_and_wait()?;
         let current_registers = self.get_registers()?;
-        Ok(current_registers.pc == initial_pc)
+        Ok(current_registers.pc() == initial_pc)
     }
 
     pub fn cancel_pending_read(&self) -> Result<()> {
@@ -63,10 +116,10 @@ impl ProcessController {
     /// returns (sysno, first arg)
     pub fn current_syscall(&self) -> Result<Option<(u64, u64)>> {
         let registers = self.get_registers()?;
-        let data = sys::ptrace::read(self.pid, registers.pc as *mut libc::c_void)?;
-        let current_instruction = (data & 0xffffffff) as u64;
-        if current_instruction as u32 == SVC {
-            Ok(Some((registers.regs[8], registers.regs[0])))
+        let addr = registers.interrupted_syscall_address();
+        let data = sys::ptrace::read(self.pid, addr as *mut libc::c_void)?;
+        if data.to_le_bytes().starts_with(&arch::SYSCALL_BYTES) {
+            Ok(Some((registers.syscall_number(), registers.arg(0))))
         } else {
             Ok(None)
         }
@@ -85,12 +138,12 @@ impl ProcessController {
         // alternatively, seems like we could single-step; if that fails to advance PC, then do PTRACE_SYSCALL
         // to wait for syscall exit
         let initial_registers = self.get_registers()?;
-        let initial_pc = initial_registers.pc;
+        let initial_pc = initial_registers.pc();
 
         loop {
             self.step_and_wait()?;
             let current_registers = self.get_registers()?;
-            if current_registers.pc != initial_pc {
+            if current_registers.pc() != initial_pc {
                 break;
             }
             // TODO: sleep for an interval and have a timeout
@@ -107,14 +160,8 @@ impl ProcessController {
 
     pub fn prepare_syscall_at_pc(&self, sysno: Sysno, args: Vec<i64>, pc: u64) -> Result<()> {
         let mut registers = self.get_registers()?;
-
-        // syscall number in x8, args in x0, x1, x2, x3...
-        registers.regs[8] = sysno.id() as u64;
-        for i in 0..args.len() {
-            registers.regs[i] = args[i] as u64;
-        }
-        registers.pc = pc;
-
+        let args: Vec<u64> = args.iter().map(|arg| *arg as u64).collect();
+        registers.prepare_syscall(sysno, &args, pc);
         self.set_registers(registers)?;
         Ok(())
     }
@@ -123,40 +170,358 @@ impl ProcessController {
         self.prepare_syscall(sysno, args)?;
         self.ensure_not_in_syscall()?;
         let registers = self.get_registers()?;
-        Ok(registers.regs[0])
+        Ok(registers.return_value())
     }
 
     pub fn execute_syscall_at_pc(&self, sysno: Sysno, args: Vec<i64>, pc: u64) -> Result<u64> {
         self.prepare_syscall_at_pc(sysno, args, pc)?;
         self.ensure_not_in_syscall()?;
         let registers = self.get_registers()?;
-        Ok(registers.regs[0])
+        Ok(registers.return_value())
     }
 
     pub fn find_svc_instruction(&self) -> Result<u64> {
-        for memory_map in self.get_memory_maps()? {
-            // [vdso] section should always have a syscall instruction
-            // more robust: look at every executable section
-            if memory_map.label == "[vdso]" {
-                return find_svc_instruction_in_map(self.pid, &memory_map);
+        if let Some(addr) = self.svc_instruction.get() {
+            return Ok(addr);
+        }
+
+        let addr = self.search_for_svc_instruction()?;
+        self.svc_instruction.set(Some(addr));
+        Ok(addr)
+    }
+
+    fn search_for_svc_instruction(&self) -> Result<u64> {
+        // [vdso] section should always have a syscall instruction, but it may be missing (vdso=0
+        // kernels, static binaries, or we unmapped it ourselves), so fall back to every other
+        // executable section, starting with libc
+        let mut candidates: Vec<&MemoryMap> = self
+            .get_memory_maps()?
+            .iter()
+            .filter(|memory_map| memory_map.executable)
+            .collect();
+        candidates.sort_by_key(|memory_map| svc_search_priority(&memory_map.label));
+
+        for memory_map in candidates {
+            match find_svc_instruction_in_map(self.pid, memory_map) {
+                Ok(addr) => return Ok(addr),
+                Err(e) => log::info!("no syscall instruction in {}: {}", memory_map, e),
+            }
+        }
+
+        log::info!("no syscall instruction in any executable section, injecting our own");
+        self.inject_svc_page()
+    }
+
+    /// maps a fresh page full of syscall instructions, for tracees that have none of their own
+    fn inject_svc_page(&self) -> Result<u64> {
+        let original_registers = self.get_registers()?;
+        let pc = original_registers.pc();
+        let p = pc as *mut libc::c_void;
+
+        // there is nowhere to borrow a syscall instruction from, so temporarily write one over
+        // the current instruction just long enough to call mmap
+        let original_data =
+            sys::ptrace::read(self.pid, p).map_err(|e| anyhow!("PTRACE_PEEKDATA failed: {}", e))?;
+        sys::ptrace::write(self.pid, p, arch::with_syscall_instruction(original_data))
+            .map_err(|e| anyhow!("PTRACE_POKEDATA failed (injecting syscall): {}", e))?;
+
+        let page_size = procfs::page_size();
+        let result = self.execute_syscall_at_pc(
+            Sysno::mmap,
+            vec![
+                0,
+                page_size as i64,
+                (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC) as i64,
+                (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as i64,
+                -1,
+                0,
+            ],
+            pc,
+        );
+
+        sys::ptrace::write(self.pid, p, original_data)
+            .map_err(|e| anyhow!("PTRACE_POKEDATA failed (restoring old data): {}", e))?;
+        self.set_registers(original_registers)?;
+
+        let addr = result?;
+        if addr as *mut libc::c_void == libc::MAP_FAILED || (addr as i64) < 0 {
+            return Err(anyhow!("mmap failed while injecting syscall page"));
+        }
+
+        let mut bytes = Vec::new();
+        for _ in 0..(page_size as usize) / arch::SYSCALL_BYTES.len() {
+            bytes.extend_from_slice(&arch::SYSCALL_BYTES[..]);
+        }
+        self.svc_page.set(Some(addr));
+        self.inject_bytes_at_addr(&bytes, addr)
+    }
+
+    /// unmaps the page `inject_svc_page` mapped, if any, so that a process that carries on
+    /// running isn't left with it, and the next checkpoint of it doesn't save it
+    ///
+    /// the registers are put back afterwards; a later injected syscall looks for a syscall
+    /// instruction again
+    pub fn remove_svc_page(&self) -> Result<()> {
+        let Some(addr) = self.svc_page.take() else {
+            return Ok(());
+        };
+        self.svc_instruction.set(None);
+
+        // the syscall instruction can come from the page itself: the thread is stopped again
+        // before it would fetch anything after it
+        let registers = self.get_registers()?;
+        let result = self.execute_syscall_at_pc(
+            Sysno::munmap,
+            vec![addr as i64, procfs::page_size() as i64],
+            addr,
+        );
+        self.set_registers(registers)?;
+        let r = result? as i64;
+        if r < 0 {
+            return Err(anyhow!(
+                "munmap of the injected syscall page failed: {}",
+                std::io::Error::from_raw_os_error(-r as i32)
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn get_registers(&self) -> Result<Registers> {
+        arch::get_registers(self.pid)
+    }
+
+    pub fn set_registers(&self, registers: Registers) -> Result<()> {
+        arch::set_registers(self.pid, &registers)
+    }
+
+    /// returns the set of blocked signals
+    pub fn get_signal_mask(&self) -> Result<u64> {
+        let mut mask: u64 = 0;
+        unsafe {
+            syscalls::syscall!(
+                Sysno::ptrace,
+                libc::PTRACE_GETSIGMASK,
+                self.pid.as_raw(),
+                std::mem::size_of::<u64>(),
+                &mut mask as *mut u64
+            )
+        }
+        .map_err(|e| anyhow!("PTRACE_GETSIGMASK failed: {}", e))?;
+        Ok(mask)
+    }
+
+    pub fn set_signal_mask(&self, mask: u64) -> Result<()> {
+        unsafe {
+            syscalls::syscall!(
+                Sysno::ptrace,
+                libc::PTRACE_SETSIGMASK,
+                self.pid.as_raw(),
+                std::mem::size_of::<u64>(),
+                &mask as *const u64
+            )
+        }
+        .map_err(|e| anyhow!("PTRACE_SETSIGMASK failed: {}", e))?;
+        Ok(())
+    }
+
+    /// returns (head, len) as registered with set_robust_list(2)
+    pub fn get_robust_list(&self) -> Result<(u64, u64)> {
+        let mut head: u64 = 0;
+        let mut len: u64 = 0;
+        unsafe {
+            syscalls::syscall!(
+                Sysno::get_robust_list,
+                self.pid.as_raw(),
+                &mut head as *mut u64,
+                &mut len as *mut u64
+            )
+        }
+        .map_err(|e| anyhow!("get_robust_list failed: {}", e))?;
+        Ok((head, len))
+    }
+
+    /// returns the address registered with set_tid_address(2), read with an injected
+    /// prctl(PR_GET_TID_ADDRESS)
+    ///
+    /// needs a kernel built with CONFIG_CHECKPOINT_RESTORE
+    pub fn get_clear_child_tid(&self) -> Result<u64> {
+        let registers = self.get_registers()?;
+        let scratch = signals::stack_scratch_address(registers.sp());
+        let saved = self.read_bytes(scratch, 8)?;
+
+        let result = self.execute_syscall(
+            Sysno::prctl,
+            vec![libc::PR_GET_TID_ADDRESS as i64, scratch as i64, 0, 0, 0],
+        );
+        let result = result.and_then(|r| {
+            if (r as i64) < 0 {
+                return Err(anyhow!(
+                    "prctl(PR_GET_TID_ADDRESS) failed: {}",
+                    std::io::Error::from_raw_os_error(-(r as i64) as i32)
+                ));
+            }
+            let bytes = self.read_bytes(scratch, 8)?;
+            Ok(u64::from_ne_bytes(bytes[..8].try_into().unwrap()))
+        });
+
+        self.inject_bytes_at_addr(&saved, scratch)?;
+        self.set_registers(registers)?;
+        result
+    }
+
+    /// reads every signal action that isn't the default with injected rt_sigaction() calls
+    ///
+    /// the registers and the bit of stack used for the result are put back afterwards, so this is
+    /// safe to call on a process that will carry on running
+    pub fn get_signal_actions(&self) -> Result<Vec<SignalAction>> {
+        let registers = self.get_registers()?;
+        let scratch = signals::stack_scratch_address(registers.sp());
+        let saved = self.read_bytes(scratch, SIGACTION_SIZE)?;
+
+        let result = self.read_signal_actions(scratch);
+
+        self.inject_bytes_at_addr(&saved, scratch)?;
+        self.set_registers(registers)?;
+        result
+    }
+
+    fn read_signal_actions(&self, scratch: u64) -> Result<Vec<SignalAction>> {
+        let mut actions = Vec::new();
+        for signal in signals::catchable_signals() {
+            let r = self.execute_syscall(
+                Sysno::rt_sigaction,
+                vec![signal as i64, 0, scratch as i64, SIGSET_SIZE as i64],
+            )? as i64;
+            if r < 0 {
+                return Err(anyhow!(
+                    "rt_sigaction({}) failed: {}",
+                    signal,
+                    std::io::Error::from_raw_os_error(-r as i32)
+                ));
+            }
+
+            let bytes = self.read_bytes(scratch, SIGACTION_SIZE)?;
+            let action = SignalAction::from_bytes(signal, &bytes)?;
+            if !action.is_default() {
+                actions.push(action);
             }
         }
+        Ok(actions)
+    }
 
-        Err(anyhow!("could not find [vdso] segment in binary"))
+    /// installs `actions` with injected rt_sigaction() calls
+    pub fn set_signal_actions(&self, svc_region_addr: u64, actions: &[SignalAction]) -> Result<()> {
+        let scratch = svc_region_addr + SVC_REGION_SCRATCH_OFFSET;
+        for action in actions {
+            self.inject_bytes_at_addr(&action.to_bytes(), scratch)?;
+            let r = self.execute_syscall_at_pc(
+                Sysno::rt_sigaction,
+                vec![action.signal as i64, scratch as i64, 0, SIGSET_SIZE as i64],
+                svc_region_addr,
+            )? as i64;
+            if r < 0 {
+                return Err(anyhow!(
+                    "rt_sigaction({}) failed: {}",
+                    action.signal,
+                    std::io::Error::from_raw_os_error(-r as i32)
+                ));
+            }
+        }
+        Ok(())
     }
 
-    pub fn get_registers(&self) -> Result<libc::user_regs_struct> {
-        let registers = sys::ptrace::getregset::<sys::ptrace::regset::NT_PRSTATUS>(self.pid)
-            .map_err(|e| anyhow!("PTRACE_GETREGSET failed: {}", e))?;
-        Ok(registers)
+    /// returns the signals queued for this thread, or with `shared`, for the whole process
+    pub fn get_pending_signals(&self, shared: bool) -> Result<Vec<QueuedSignal>> {
+        #[repr(C)]
+        struct PeekSiginfoArgs {
+            off: u64,
+            flags: u32,
+            nr: i32,
+        }
+
+        let mut r = Vec::new();
+        let mut off = 0;
+        loop {
+            let args = PeekSiginfoArgs {
+                off,
+                flags: if shared { PTRACE_PEEKSIGINFO_SHARED } else { 0 },
+                nr: 1,
+            };
+            let mut siginfo = vec![0u8; SIGINFO_SIZE];
+            let n = unsafe {
+                syscalls::syscall!(
+                    Sysno::ptrace,
+                    libc::PTRACE_PEEKSIGINFO,
+                    self.pid.as_raw(),
+                    &args as *const PeekSiginfoArgs,
+                    siginfo.as_mut_ptr()
+                )
+            }
+            .map_err(|e| anyhow!("PTRACE_PEEKSIGINFO failed: {}", e))?;
+            if n == 0 {
+                break;
+            }
+            off += 1;
+
+            let signal = QueuedSignal { siginfo };
+            // stopping the process for the freeze is our doing, not something to replay, but a
+            // SIGSTOP that someone else sent is
+            if !is_our_sigstop(&signal) {
+                r.push(signal);
+            }
+        }
+        Ok(r)
     }
 
-    pub fn set_registers(&self, registers: libc::user_regs_struct) -> Result<()> {
-        sys::ptrace::setregset::<sys::ptrace::regset::NT_PRSTATUS>(self.pid, registers)
-            .map_err(|e| anyhow!("PTRACE_SETREGSET failed: {}", e))?;
+    /// queues `signal` again with an injected syscall, for the whole process or just thread `tid`
+    ///
+    /// has to be called on the thread group leader: the kernel only lets a process queue
+    /// arbitrary siginfo for itself
+    pub fn queue_signal(
+        &self,
+        svc_region_addr: u64,
+        tid: Option<unistd::Pid>,
+        signal: &QueuedSignal,
+    ) -> Result<()> {
+        let scratch = svc_region_addr + SVC_REGION_SCRATCH_OFFSET;
+        self.inject_bytes_at_addr(&signal.siginfo, scratch)?;
+
+        // our pid isn't necessarily the one the tracee knows itself by, e.g. in a pid namespace
+        let pid = self.execute_syscall_at_pc(Sysno::getpid, vec![], svc_region_addr)? as i64;
+        let (sysno, args) = match tid {
+            Some(tid) => (
+                Sysno::rt_tgsigqueueinfo,
+                vec![
+                    pid,
+                    tid.as_raw() as i64,
+                    signal.signal() as i64,
+                    scratch as i64,
+                ],
+            ),
+            None => (
+                Sysno::rt_sigqueueinfo,
+                vec![pid, signal.signal() as i64, scratch as i64],
+            ),
+        };
+        let r = self.execute_syscall_at_pc(sysno, args, svc_region_addr)? as i64;
+        if r < 0 {
+            return Err(anyhow!(
+                "{} failed: {}",
+                sysno.name(),
+                std::io::Error::from_raw_os_error(-r as i32)
+            ));
+        }
         Ok(())
     }
 
+    pub fn get_fp_registers(&self) -> Result<FpRegisters> {
+        arch::get_fp_registers(self.pid)
+    }
+
+    pub fn set_fp_registers(&self, fp_registers: &FpRegisters) -> Result<()> {
+        arch::set_fp_registers(self.pid, fp_registers)
+    }
+
     pub fn wait_for_syscall(&self) -> Result<()> {
         sys::ptrace::syscall(self.pid, None)
             .map_err(|e| anyhow!("PTRACE_SYSCALL failed: {}", e))?;
@@ -189,10 +554,10 @@ impl ProcessController {
     pub fn is_writing_to_stdout(&self) -> Result<Option<(u64, u64)>> {
         let registers = self.get_registers()?;
 
-        if registers.regs[8] == Sysno::write.id() as u64
-            && registers.regs[0] == libc::STDOUT_FILENO as u64
+        if registers.syscall_number() == Sysno::write.id() as u64
+            && registers.arg(0) == libc::STDOUT_FILENO as u64
         {
-            Ok(Some((registers.regs[1], registers.regs[2])))
+            Ok(Some((registers.arg(1), registers.arg(2))))
         } else {
             Ok(None)
         }
@@ -202,10 +567,10 @@ impl ProcessController {
     pub fn is_writing_to_stderr(&self) -> Result<Option<(u64, u64)>> {
         let registers = self.get_registers()?;
 
-        if registers.regs[8] == Sysno::write.id() as u64
-            && registers.regs[0] == libc::STDERR_FILENO as u64
+        if registers.syscall_number() == Sysno::write.id() as u64
+            && registers.arg(0) == libc::STDERR_FILENO as u64
         {
-            Ok(Some((registers.regs[1], registers.regs[2])))
+            Ok(Some((registers.arg(1), registers.arg(2))))
         } else {
             Ok(None)
         }
@@ -244,13 +609,34 @@ impl ProcessController {
             i += 8;
         }
 
-        original_regs.regs[1] = region_addr;
-        original_regs.regs[2] = original_length as u64;
+        original_regs.set_arg(1, region_addr);
+        original_regs.set_arg(2, original_length as u64);
         self.set_registers(original_regs)?;
 
         Ok(())
     }
 
+    pub fn read_bytes(&self, base_addr: u64, count: usize) -> Result<Vec<u8>> {
+        let mut buffer = vec![0; count];
+        let local_iov = &mut [IoSliceMut::new(&mut buffer[..])];
+        let remote_iov = sys::uio::RemoteIoVec {
+            base: base_addr as usize,
+            len: count,
+        };
+
+        let nread = sys::uio::process_vm_readv(self.pid, local_iov, &[remote_iov])
+            .map_err(|e| anyhow!("process_vm_readv failed at {:#x}: {}", base_addr, e))?;
+        if nread != count {
+            return Err(anyhow!(
+                "process_vm_readv only read {} of {} byte(s) at {:#x}",
+                nread,
+                count,
+                base_addr
+            ));
+        }
+        Ok(buffer)
+    }
+
     pub fn read_string(&self, base_addr: u64, count: u64) -> Result<String> {
         let mut buffer = vec![0; count as usize];
         let local_iov = &mut [IoSliceMut::new(&mut buffer[..])];
@@ -286,8 +672,12 @@ impl ProcessController {
             highest_addr = std::cmp::max(memory_map.base_address + memory_map.size, highest_addr);
         }
 
-        let addr = highest_addr + 4096;
-        let region_size = 4096;
+        self.map_svc_region_at(highest_addr + 4096)
+    }
+
+    /// like `map_svc_region`, but at `addr`, which has to be free
+    pub fn map_svc_region_at(&self, addr: u64) -> Result<u64> {
+        let region_size = SVC_REGION_SIZE as i64;
         println!("trying to map to addr {:#x}", addr);
         let r = self.execute_syscall(
             Sysno::mmap,
@@ -303,12 +693,19 @@ impl ProcessController {
         if r as *mut libc::c_void == libc::MAP_FAILED {
             return Err(anyhow!("mmap failed at {:#x} (size={})", addr, region_size));
         }
+        if r != addr {
+            return Err(anyhow!(
+                "mmap put the svc region at {:#x} instead of {:#x}",
+                r,
+                addr
+            ));
+        }
 
         println!("mmap returned {:#x}", r);
 
         let mut bytes = Vec::new();
-        for _ in 0..(region_size as usize) / SVC_BYTES.len() {
-            bytes.extend_from_slice(&SVC_BYTES[..]);
+        for _ in 0..(region_size as usize) / arch::SYSCALL_BYTES.len() {
+            bytes.extend_from_slice(&arch::SYSCALL_BYTES[..]);
         }
 
         let local_iov = IoSlice::new(&bytes);
@@ -325,6 +722,42 @@ impl ProcessController {
         Ok(addr)
     }
 
+    /// points the tracee at `arch::REAPER_CODE`, copied into the svc region's scratch area
+    pub fn become_reaper(&self, svc_region_addr: u64) -> Result<()> {
+        let addr = self.write_scratch(svc_region_addr, &arch::REAPER_CODE)?;
+        let mut registers = self.get_registers()?;
+        registers.set_pc(addr);
+        self.set_registers(registers)
+    }
+
+    /// unmaps everything but the svc region and the vDSO, leaving a blank address space
+    pub fn unmap_all_except_svc_region(&mut self, svc_region_addr: u64) -> Result<()> {
+        for memory_map in myprocfs::read_memory_maps(self.pid.as_raw())? {
+            // the vDSO is the kernel's, and [vsyscall] can't be unmapped at all
+            if memory_map.base_address == svc_region_addr || memory_map.is_kernel_provided() {
+                continue;
+            }
+
+            let r = self.execute_syscall_at_pc(
+                Sysno::munmap,
+                vec![memory_map.base_address as i64, memory_map.size as i64],
+                svc_region_addr,
+            )? as i64;
+            if r < 0 {
+                return Err(anyhow!(
+                    "failed to unmap {}: {}",
+                    memory_map,
+                    std::io::Error::from_raw_os_error(-r as i32)
+                ));
+            }
+        }
+
+        // whatever was cached pointed into what is now gone
+        self.memory_maps = OnceCell::new();
+        self.svc_instruction.set(Some(svc_region_addr));
+        Ok(())
+    }
+
     fn get_segment_address(&self, label: &str) -> Result<(u64, u64)> {
         for map in self.get_memory_maps()? {
             if map.label == label {
@@ -391,6 +824,30 @@ impl ProcessController {
         svc_region_addr: u64,
         memory_map: &myprocfs::MemoryMap,
     ) -> Result<()> {
+        self.restore_region(svc_region_addr, memory_map, true)
+    }
+
+    /// like `map_and_fill_region`, but leaves out the stored pages, for `lazy` to fill in once
+    /// they are touched
+    pub fn map_region_without_contents(
+        &self,
+        svc_region_addr: u64,
+        memory_map: &myprocfs::MemoryMap,
+    ) -> Result<()> {
+        self.restore_region(svc_region_addr, memory_map, false)
+    }
+
+    fn restore_region(
+        &self,
+        svc_region_addr: u64,
+        memory_map: &myprocfs::MemoryMap,
+        fill: bool,
+    ) -> Result<()> {
+        // check before unmapping anything, so a stale file doesn't leave a hole in the process
+        if let Some(backing) = &memory_map.file {
+            backing.verify(memory_map)?;
+        }
+
         self.execute_syscall_at_pc(
             Sysno::munmap,
             vec![memory_map.base_address as i64, memory_map.size as i64],
@@ -410,6 +867,54 @@ impl ProcessController {
             prot |= libc::PROT_EXEC;
         }
 
+        if memory_map.file.is_some() {
+            self.map_file_region(svc_region_addr, memory_map, prot)?;
+        } else {
+            self.map_anonymous_region(svc_region_addr, memory_map, prot)?;
+        }
+
+        // the fresh mapping already holds zeros or the file's contents, so only the populated
+        // pages need to be written
+        let runs = if fill {
+            memory_map.populated_runs()
+        } else {
+            Vec::new()
+        };
+        for (addr, bytes) in runs {
+            let local_iov = IoSlice::new(bytes);
+            let remote_iov = sys::uio::RemoteIoVec {
+                base: addr as usize,
+                len: bytes.len(),
+            };
+            let nwritten = sys::uio::process_vm_writev(self.pid, &[local_iov], &[remote_iov])
+                .map_err(|e| anyhow!("process_vm_writev failed at {:#x}: {}", addr, e))?;
+            if nwritten == 0 {
+                return Err(anyhow!("failed to write data"));
+            }
+        }
+
+        if !memory_map.writable {
+            // if it wasn't supposed to be writable, fix it
+            self.execute_syscall_at_pc(
+                Sysno::mprotect,
+                vec![
+                    memory_map.base_address as i64,
+                    memory_map.size as i64,
+                    prot as i64,
+                ],
+                svc_region_addr,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn map_anonymous_region(
+        &self,
+        svc_region_addr: u64,
+        memory_map: &myprocfs::MemoryMap,
+        prot: i32,
+    ) -> Result<()> {
         // TODO: this definitely doesn't handle shared memory correctly
         let options = libc::MAP_ANONYMOUS | libc::MAP_PRIVATE;
         // if memory_map.private {
@@ -437,43 +942,571 @@ impl ProcessController {
             ));
         }
 
-        if memory_map.data.len() > 0 {
-            let local_iov = IoSlice::new(&memory_map.data);
-            let remote_iov = sys::uio::RemoteIoVec {
-                base: memory_map.base_address as usize,
-                len: memory_map.data.len(),
-            };
-            let nwritten = sys::uio::process_vm_writev(self.pid, &[local_iov], &[remote_iov])
-                .map_err(|e| anyhow!("process_vm_writev failed: {}", e))?;
-            if nwritten == 0 {
-                return Err(anyhow!("failed to write data"));
+        Ok(())
+    }
+
+    /// maps `memory_map.label` back in at its original address and offset
+    fn map_file_region(
+        &self,
+        svc_region_addr: u64,
+        memory_map: &myprocfs::MemoryMap,
+        prot: i32,
+    ) -> Result<()> {
+        let shared_writable = !memory_map.private && memory_map.writable;
+        let flags = if shared_writable {
+            libc::O_RDWR
+        } else {
+            libc::O_RDONLY
+        };
+        let fd = self.open_file(svc_region_addr, &memory_map.label, flags)?;
+
+        let mut map_prot = prot;
+        if !memory_map.runs.is_empty() {
+            // we need it to be writable to copy the dirty pages over
+            map_prot |= libc::PROT_WRITE;
+        }
+        let options = if memory_map.private {
+            libc::MAP_PRIVATE | libc::MAP_FIXED
+        } else {
+            libc::MAP_SHARED | libc::MAP_FIXED
+        };
+        let r = self.execute_syscall_at_pc(
+            Sysno::mmap,
+            vec![
+                memory_map.base_address as i64,
+                memory_map.size as i64,
+                map_prot as i64,
+                options as i64,
+                fd,
+                memory_map.offset as i64,
+            ],
+            svc_region_addr,
+        );
+        self.execute_syscall_at_pc(Sysno::close, vec![fd], svc_region_addr)?;
+
+        let r = r?;
+        if r != memory_map.base_address {
+            return Err(anyhow!(
+                "mmap of {} failed at {:#x} (size={}): returned {:#x}",
+                memory_map.label,
+                memory_map.base_address,
+                memory_map.size,
+                r
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// puts the kernel's vDSO where the one in `memory_maps` was, see `vdso`; the regions the
+    /// kernel provides are skipped when restoring the others, and this has to come first so that
+    /// they aren't in the way of any of them
+    pub fn relocate_vdso(&self, svc_region_addr: u64, memory_maps: &[MemoryMap]) -> Result<()> {
+        let old = match memory_maps.iter().find(|map| map.label == "[vdso]") {
+            Some(old) => old,
+            None => return Ok(()),
+        };
+        let kernel_maps: Vec<MemoryMap> = myprocfs::read_memory_maps(self.pid.as_raw())?
+            .into_iter()
+            .filter(|map| map.is_kernel_provided() && map.label != "[vsyscall]")
+            .collect();
+        let new = kernel_maps
+            .iter()
+            .find(|map| map.label == "[vdso]")
+            .ok_or(anyhow!("the new process has no vDSO"))?;
+        let mut old_image = vdso::saved_image(old);
+        let new_image = self.read_bytes(new.base_address, new.size as usize)?;
+
+        // the vDSO finds its [vvar] pages relative to itself, so those have to keep their places
+        // around it as well
+        let recorded = |map: &MemoryMap| {
+            memory_maps
+                .iter()
+                .find(|m| {
+                    m.label == map.label
+                        && m.size == map.size
+                        && m.base_address.wrapping_sub(old.base_address)
+                            == map.base_address.wrapping_sub(new.base_address)
+                })
+                .map(|m| m.base_address)
+        };
+        let targets: Option<Vec<u64>> = if vdso::lines_up(&old_image, &new_image)? {
+            kernel_maps.iter().map(recorded).collect()
+        } else {
+            None
+        };
+        let in_place = |targets: &Vec<u64>| {
+            kernel_maps
+                .iter()
+                .zip(targets)
+                .all(|(map, target)| map.base_address == *target)
+        };
+        if targets.as_ref().is_some_and(in_place) {
+            return Ok(());
+        }
+
+        // out of the way of everything, first, as the kernel's regions may overlap where they
+        // are going, or where the process has something else
+        let start = kernel_maps
+            .iter()
+            .map(|map| map.base_address)
+            .min()
+            .unwrap();
+        let end = kernel_maps
+            .iter()
+            .map(|map| map.base_address + map.size)
+            .max()
+            .unwrap();
+        let mut taken: Vec<(u64, u64)> = memory_maps
+            .iter()
+            .chain(myprocfs::read_memory_maps(self.pid.as_raw())?.iter())
+            .map(|map| (map.base_address, map.base_address + map.size))
+            .collect();
+        taken.push((svc_region_addr, svc_region_addr + SVC_REGION_SIZE));
+        let free = restorer::find_free_address(&taken, end - start);
+        let staged: Vec<u64> = kernel_maps
+            .iter()
+            .map(|map| free + map.base_address - start)
+            .collect();
+        for (map, to) in kernel_maps.iter().zip(&staged) {
+            self.move_region(svc_region_addr, map.base_address, map.size, *to)?;
+        }
+        if let Some(targets) = targets {
+            for ((map, from), to) in kernel_maps.iter().zip(staged).zip(targets) {
+                self.move_region(svc_region_addr, from, map.size, to)?;
             }
+            return Ok(());
         }
 
-        if !memory_map.writable {
-            // if it wasn't supposed to be writable, fix it
-            self.execute_syscall_at_pc(
-                Sysno::mprotect,
+        let new_base = free + new.base_address - start;
+        log::info!(
+            "the vDSO doesn't match the one at {:#x}; redirecting it to the new one at {:#x}",
+            old.base_address,
+            new_base
+        );
+        let missing = vdso::redirect(&mut old_image, &new_image, new_base)?;
+        if !missing.is_empty() {
+            log::warn!(
+                "could not redirect {}; calls to it will likely crash",
+                missing.join(", ")
+            );
+        }
+        let copy = MemoryMap {
+            label: old.label.clone(),
+            file: None,
+            runs: vec![PageRun {
+                offset: 0,
+                len: old.size,
+            }],
+            data: old_image,
+            holes: Vec::new(),
+            ..*old
+        };
+        self.map_and_fill_region(svc_region_addr, &copy)
+    }
+
+    fn move_region(&self, svc_region_addr: u64, from: u64, size: u64, to: u64) -> Result<()> {
+        self.execute_checked(
+            svc_region_addr,
+            Sysno::mremap,
+            vec![
+                from as i64,
+                size as i64,
+                size as i64,
+                (libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED) as i64,
+                to as i64,
+            ],
+        )
+        .map_err(|e| anyhow!("failed to move {:#x} to {:#x}: {}", from, to, e))?;
+        Ok(())
+    }
+
+    /// injects clone() to start a new thread in the tracee's thread group and returns its tid
+    ///
+    /// the new thread is attached and stopped; it starts out with a copy of our registers, so the
+    /// caller is expected to overwrite them before letting it run
+    ///
+    /// with `tid`, the thread gets that id (as seen from the tracee's pid namespace) or the call
+    /// fails
+    pub fn clone_thread(
+        &self,
+        svc_region_addr: u64,
+        tid: Option<unistd::Pid>,
+    ) -> Result<unistd::Pid> {
+        let flags = libc::CLONE_VM
+            | libc::CLONE_FS
+            | libc::CLONE_FILES
+            | libc::CLONE_SIGHAND
+            | libc::CLONE_THREAD
+            | libc::CLONE_SYSVSEM;
+        self.inject_clone(svc_region_addr, flags, 0, libc::PTRACE_EVENT_CLONE, tid)
+    }
+
+    /// injects fork() into the tracee and returns the child's pid
+    ///
+    /// as with `clone_thread`, the child is attached and stopped before it runs any code, and
+    /// `pid` asks for a particular pid
+    pub fn fork_process(
+        &self,
+        svc_region_addr: u64,
+        pid: Option<unistd::Pid>,
+    ) -> Result<unistd::Pid> {
+        self.inject_clone(
+            svc_region_addr,
+            0,
+            libc::SIGCHLD,
+            libc::PTRACE_EVENT_FORK,
+            pid,
+        )
+    }
+
+    fn inject_clone(
+        &self,
+        svc_region_addr: u64,
+        flags: i32,
+        exit_signal: i32,
+        event: i32,
+        tid: Option<unistd::Pid>,
+    ) -> Result<unistd::Pid> {
+        sys::ptrace::setoptions(
+            self.pid,
+            sys::ptrace::Options::PTRACE_O_TRACECLONE | sys::ptrace::Options::PTRACE_O_TRACEFORK,
+        )
+        .map_err(|e| anyhow!("PTRACE_SETOPTIONS failed: {}", e))?;
+
+        // a null stack means the child shares ours, which is fine as it never runs with it
+        match tid {
+            Some(tid) => {
+                // only clone3() can pick the id, and it wants it in memory
+                let scratch = svc_region_addr + SVC_REGION_SCRATCH_OFFSET;
+                let set_tid = scratch + restorer::CLONE_ARGS_SIZE as u64;
+                let args = restorer::CloneArgs {
+                    flags: flags as u64,
+                    exit_signal: exit_signal as u64,
+                    set_tid,
+                    set_tid_size: 1,
+                    ..Default::default()
+                };
+                let mut bytes = args.to_bytes();
+                bytes.extend_from_slice(&tid.as_raw().to_ne_bytes());
+                self.write_scratch(svc_region_addr, &bytes)?;
+                self.prepare_syscall_at_pc(
+                    Sysno::clone3,
+                    vec![scratch as i64, restorer::CLONE_ARGS_SIZE as i64],
+                    svc_region_addr,
+                )?;
+            }
+            None => self.prepare_syscall_at_pc(
+                Sysno::clone,
+                vec![(flags | exit_signal) as i64, 0, 0, 0, 0],
+                svc_region_addr,
+            )?,
+        }
+
+        sys::ptrace::step(self.pid, None)
+            .map_err(|e| anyhow!("PTRACE_SINGLESTEP failed: {}", e))?;
+        let status = sys::wait::waitpid(self.pid, Some(sys::wait::WaitPidFlag::WSTOPPED))
+            .map_err(|e| anyhow!("failed to waitpid (clone): {}", e))?;
+        match (status, tid) {
+            (sys::wait::WaitStatus::PtraceEvent(_, _, e), _) if e == event => {}
+            // no event means nothing was created
+            (sys::wait::WaitStatus::Stopped(_, sys::signal::SIGTRAP), Some(tid)) => {
+                let errno = -(self.get_registers()?.return_value() as i64) as i32;
+                if errno != libc::ENOSYS {
+                    return Err(restorer::set_tid_error(tid.as_raw(), errno));
+                }
+
+                // no clone3(), so fall back to racing for the id, which only works in our own pid
+                // namespace
+                if !restorer::shares_pid_namespace(self.pid.as_raw())? {
+                    return Err(anyhow!(
+                        "can't choose id {} in another pid namespace without clone3() (Linux 5.5)",
+                        tid
+                    ));
+                }
+                restorer::set_ns_last_pid(tid.as_raw())?;
+                let child = self.inject_clone(svc_region_addr, flags, exit_signal, event, None)?;
+                if child != tid {
+                    return Err(anyhow!(
+                        "got id {} instead of {}: something else forked in between",
+                        child,
+                        tid
+                    ));
+                }
+                return Ok(child);
+            }
+            (status, _) => return Err(anyhow!("expected a clone event but got {:?}", status)),
+        }
+        let tid = sys::ptrace::getevent(self.pid)
+            .map_err(|e| anyhow!("PTRACE_GETEVENTMSG failed: {}", e))?;
+        let tid = unistd::Pid::from_raw(tid as i32);
+
+        // finish the syscall in the parent, and wait for the child's initial SIGSTOP
+        self.step_and_wait()?;
+        sys::wait::waitpid(tid, Some(sys::wait::WaitPidFlag::__WALL))
+            .map_err(|e| anyhow!("failed to waitpid for new child {}: {}", tid, e))?;
+
+        Ok(tid)
+    }
+
+    /// reopens `open_file` in the tracee at its original fd number
+    pub fn restore_open_file(&self, svc_region_addr: u64, open_file: &OpenFile) -> Result<()> {
+        let cloexec = if open_file.close_on_exec {
+            libc::O_CLOEXEC
+        } else {
+            0
+        };
+        // the file already exists, so don't let it be recreated or truncated
+        let flags = open_file.flags & !(libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC);
+        let fd = self.open_file(svc_region_addr, &open_file.path, flags | cloexec)?;
+
+        if fd != open_file.fd as i64 {
+            let r = self.execute_syscall_at_pc(
+                Sysno::dup3,
+                vec![fd, open_file.fd as i64, cloexec as i64],
+                svc_region_addr,
+            )? as i64;
+            self.execute_syscall_at_pc(Sysno::close, vec![fd], svc_region_addr)?;
+            if r < 0 {
+                return Err(anyhow!(
+                    "dup3 to fd {} failed: {}",
+                    open_file.fd,
+                    std::io::Error::from_raw_os_error(-r as i32)
+                ));
+            }
+        }
+
+        if open_file.offset != 0 {
+            let r = self.execute_syscall_at_pc(
+                Sysno::lseek,
                 vec![
-                    memory_map.base_address as i64,
-                    memory_map.size as i64,
-                    prot as i64,
+                    open_file.fd as i64,
+                    open_file.offset as i64,
+                    libc::SEEK_SET as i64,
                 ],
                 svc_region_addr,
-            )?;
+            )? as i64;
+            if r < 0 {
+                return Err(anyhow!(
+                    "lseek on fd {} failed: {}",
+                    open_file.fd,
+                    std::io::Error::from_raw_os_error(-r as i32)
+                ));
+            }
         }
 
         Ok(())
     }
 
-    pub fn step_and_wait(&self) -> Result<()> {
-        sys::ptrace::step(self.pid, None)
-            .map_err(|e| anyhow!("PTRACE_SINGLESTEP failed: {}", e))?;
-        sys::wait::waitpid(self.pid, Some(sys::wait::WaitPidFlag::WSTOPPED))
-            .map_err(|e| anyhow!("failed to waitpid (syscall injection): {}", e))?;
+    /// injects openat() for `path` and returns the new fd
+    fn open_file(&self, svc_region_addr: u64, path: &str, flags: i32) -> Result<i64> {
+        // the path has to be in the tracee's memory for openat
+        let path_addr = self.write_scratch_string(svc_region_addr, path)?;
+
+        let fd = self.execute_syscall_at_pc(
+            Sysno::openat,
+            vec![libc::AT_FDCWD as i64, path_addr as i64, flags as i64, 0],
+            svc_region_addr,
+        )? as i64;
+        if fd < 0 {
+            return Err(anyhow!(
+                "failed to open {}: {}",
+                path,
+                std::io::Error::from_raw_os_error(-fd as i32)
+            ));
+        }
+        Ok(fd)
+    }
+
+    pub fn set_cwd(&self, svc_region_addr: u64, path: &str) -> Result<()> {
+        let path_addr = self.write_scratch_string(svc_region_addr, path)?;
+        self.execute_checked(svc_region_addr, Sysno::chdir, vec![path_addr as i64])?;
+        Ok(())
+    }
+
+    pub fn set_umask(&self, svc_region_addr: u64, umask: u32) -> Result<()> {
+        self.execute_checked(svc_region_addr, Sysno::umask, vec![umask as i64])?;
+        Ok(())
+    }
+
+    pub fn set_rlimit(&self, svc_region_addr: u64, limit: &ResourceLimit) -> Result<()> {
+        // struct rlimit64 { rlim_cur, rlim_max }
+        let mut bytes = limit.soft.to_ne_bytes().to_vec();
+        bytes.extend_from_slice(&limit.hard.to_ne_bytes());
+        let addr = self.write_scratch(svc_region_addr, &bytes)?;
+
+        self.execute_checked(
+            svc_region_addr,
+            Sysno::prlimit64,
+            vec![0, limit.resource as i64, addr as i64, 0],
+        )?;
+        Ok(())
+    }
+
+    /// returns the current program break with an injected brk(0), putting the registers back
+    pub fn get_brk(&self) -> Result<u64> {
+        let registers = self.get_registers()?;
+        let result = self.execute_syscall(Sysno::brk, vec![0]);
+        self.set_registers(registers)?;
+        result
+    }
+
+    /// points the kernel's idea of the heap, stack, arguments and environment at `layout`
+    ///
+    /// needs CAP_SYS_RESOURCE (or CAP_CHECKPOINT_RESTORE) in the thawing user namespace
+    pub fn set_memory_layout(&self, svc_region_addr: u64, layout: &MemoryLayout) -> Result<()> {
+        let scratch = svc_region_addr + SVC_REGION_SCRATCH_OFFSET;
+        let auxv_addr = scratch + PRCTL_MM_MAP_SIZE as u64;
+        let mut bytes = layout.to_prctl_mm_map(auxv_addr);
+        bytes.extend_from_slice(&layout.auxv);
+        self.write_scratch(svc_region_addr, &bytes)?;
+
+        let r = self.execute_syscall_at_pc(
+            Sysno::prctl,
+            vec![
+                libc::PR_SET_MM as i64,
+                libc::PR_SET_MM_MAP as i64,
+                scratch as i64,
+                PRCTL_MM_MAP_SIZE as i64,
+                0,
+            ],
+            svc_region_addr,
+        )? as i64;
+        if r == -(libc::EPERM as i64) {
+            return Err(anyhow!(
+                "prctl(PR_SET_MM_MAP) was denied; restoring brk, the stack and the command line \
+                 needs CAP_SYS_RESOURCE, e.g. running as root"
+            ));
+        }
+        if r < 0 {
+            return Err(anyhow!(
+                "prctl(PR_SET_MM_MAP) failed: {}",
+                std::io::Error::from_raw_os_error(-r as i32)
+            ));
+        }
+        Ok(())
+    }
+
+    /// sets the name shown by ps, which the kernel cuts to 15 bytes
+    pub fn set_name(&self, svc_region_addr: u64, name: &str) -> Result<()> {
+        let name_addr = self.write_scratch_string(svc_region_addr, name)?;
+        self.execute_checked(
+            svc_region_addr,
+            Sysno::prctl,
+            vec![libc::PR_SET_NAME as i64, name_addr as i64],
+        )?;
         Ok(())
     }
 
+    /// copies `bytes` to the scratch area of the svc region and returns their address there
+    fn write_scratch(&self, svc_region_addr: u64, bytes: &[u8]) -> Result<u64> {
+        if bytes.len() as u64 > SVC_REGION_SIZE - SVC_REGION_SCRATCH_OFFSET {
+            return Err(anyhow!(
+                "{} byte(s) don't fit in the scratch area",
+                bytes.len()
+            ));
+        }
+        let addr = svc_region_addr + SVC_REGION_SCRATCH_OFFSET;
+        self.inject_bytes_at_addr(bytes, addr)?;
+        Ok(addr)
+    }
+
+    fn write_scratch_string(&self, svc_region_addr: u64, s: &str) -> Result<u64> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        self.write_scratch(svc_region_addr, &bytes)
+            .map_err(|e| anyhow!("{}: {}", s, e))
+    }
+
+    /// like `execute_syscall_at_pc`, but turns a negative return value into an error
+    fn execute_checked(&self, svc_region_addr: u64, sysno: Sysno, args: Vec<i64>) -> Result<u64> {
+        let r = self.execute_syscall_at_pc(sysno, args, svc_region_addr)? as i64;
+        if r < 0 {
+            return Err(anyhow!(
+                "{} failed: {}",
+                sysno.name(),
+                std::io::Error::from_raw_os_error(-r as i32)
+            ));
+        }
+        Ok(r as u64)
+    }
+
+    /// creates a userfaultfd for the tracee's memory and returns our own copy of it, leaving none
+    /// behind in the tracee
+    pub fn take_userfaultfd(&self, svc_region_addr: u64) -> Result<OwnedFd> {
+        let remote_fd = self
+            .execute_checked(
+                svc_region_addr,
+                Sysno::userfaultfd,
+                vec![(libc::O_CLOEXEC | libc::O_NONBLOCK) as i64],
+            )
+            .map_err(|e| {
+                anyhow!(
+                    "{} (needs CAP_SYS_PTRACE, or vm.unprivileged_userfaultfd set)",
+                    e
+                )
+            })?;
+
+        let pidfd = pidfd_open(self.pid)?;
+        let r = unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), remote_fd, 0) };
+        let local_fd = if r < 0 {
+            Err(anyhow!(
+                "pidfd_getfd failed: {}",
+                std::io::Error::last_os_error()
+            ))
+        } else {
+            Ok(unsafe { OwnedFd::from_raw_fd(r as i32) })
+        };
+        self.execute_syscall_at_pc(Sysno::close, vec![remote_fd as i64], svc_region_addr)?;
+        local_fd
+    }
+
+    /// closes every fd in the tracee that isn't in `keep`
+    pub fn close_other_fds(&self, svc_region_addr: u64, keep: &[i32]) -> Result<()> {
+        let dir = format!("/proc/{}/fd", self.pid);
+        for entry in std::fs::read_dir(&dir)? {
+            let fd = match entry?
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<i32>().ok())
+            {
+                Some(fd) => fd,
+                None => continue,
+            };
+            if !keep.contains(&fd) {
+                self.execute_syscall_at_pc(Sysno::close, vec![fd as i64], svc_region_addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// single-steps one instruction
+    ///
+    /// a signal that turns up first (a signal-delivery-stop) would be dropped by resuming without
+    /// it, so it is passed back in and the step tried again; block signals with `set_signal_mask`
+    /// to keep them pending instead
+    pub fn step_and_wait(&self) -> Result<()> {
+        let mut signal = None;
+        loop {
+            sys::ptrace::step(self.pid, signal.take())
+                .map_err(|e| anyhow!("PTRACE_SINGLESTEP failed: {}", e))?;
+            let status = sys::wait::waitpid(self.pid, Some(sys::wait::WaitPidFlag::WSTOPPED))
+                .map_err(|e| anyhow!("failed to waitpid (syscall injection): {}", e))?;
+            match status {
+                sys::wait::WaitStatus::Stopped(_, sys::signal::SIGTRAP) => return Ok(()),
+                // still to come from PTRACE_INTERRUPT, or a group-stop of a seized thread
+                sys::wait::WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_STOP) => {}
+                // a group-stop has no siginfo and nothing to pass on
+                sys::wait::WaitStatus::Stopped(_, stop_signal) => {
+                    if sys::ptrace::getsiginfo(self.pid).is_ok() {
+                        signal = Some(stop_signal);
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
     pub fn inject_bytes_at_addr(&self, bytes: &[u8], addr: u64) -> Result<u64> {
         let local_iov = IoSlice::new(bytes);
         let remote_iov = sys::uio::RemoteIoVec {
@@ -555,6 +1588,42 @@ impl Drop for ProcessController {
     }
 }
 
+/// turns a freshly spawned restorer into a blank process to restore into, with nothing but an
+/// svc region outside all of the `reserved` (start, end) ranges and the vDSO; returns it stopped
+/// and attached, with the address of its svc region
+pub fn prepare_restorer(
+    pid: unistd::Pid,
+    reserved: &[(u64, u64)],
+) -> Result<(ProcessController, u64)> {
+    let mut controller = ProcessController::new(pid);
+
+    let mut taken = reserved.to_vec();
+    for memory_map in controller.get_memory_maps()? {
+        taken.push((
+            memory_map.base_address,
+            memory_map.base_address + memory_map.size,
+        ));
+    }
+    let svc_region_addr =
+        controller.map_svc_region_at(restorer::find_free_address(&taken, SVC_REGION_SIZE))?;
+    controller.unmap_all_except_svc_region(svc_region_addr)?;
+
+    Ok((controller, svc_region_addr))
+}
+
+/// returns a pidfd for `pid`, which becomes readable once the process exits
+pub fn pidfd_open(pid: unistd::Pid) -> Result<OwnedFd> {
+    let r = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    if r < 0 {
+        return Err(anyhow!(
+            "pidfd_open failed for {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(r as i32) })
+}
+
 pub fn takeover(pid: unistd::Pid, path_to_program: &str, pause: bool) -> Result<()> {
     let controller = ProcessController::new(pid);
 
@@ -588,22 +1657,38 @@ fn find_svc_instruction_in_map(pid: unistd::Pid, memory_map: &MemoryMap) -> Resu
         return Err(anyhow!("failed to read any data"));
     }
 
-    for i in 0..buffer.len() - 3 {
-        if buffer[i] == SVC_BYTES[0]
-            && buffer[i + 1] == SVC_BYTES[1]
-            && buffer[i + 2] == SVC_BYTES[2]
-            && buffer[i + 3] == SVC_BYTES[3]
-        {
-            return Ok(memory_map.base_address + i as u64);
-        }
+    if let Some(i) = arch::find_syscall_instruction(&buffer[..nread]) {
+        return Ok(memory_map.base_address + i as u64);
     }
 
-    Err(anyhow!("could not find svc instruction in segment"))
+    Err(anyhow!("could not find syscall instruction in segment"))
 }
 
-const SVC: u32 = 0xd4000001;
-// little-endian representation: 0x01 0x00 0x00 0xd4
-const SVC_BYTES: [u8; 4] = [0x01, 0x00, 0x00, 0xd4];
+/// lower is searched first
+fn svc_search_priority(label: &str) -> u8 {
+    if label == "[vdso]" {
+        return 0;
+    }
+
+    let file_name = Path::new(label)
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("");
+    // e.g., libc.so.6 or libc-2.31.so
+    if file_name.starts_with("libc.so") || file_name.starts_with("libc-") {
+        1
+    } else {
+        2
+    }
+}
+
+/// the SIGSTOP that PTRACE_ATTACH sends, which comes from the kernel, or the one `freeze_all` sent
+/// with kill(2)
+fn is_our_sigstop(signal: &QueuedSignal) -> bool {
+    signal.signal() == libc::SIGSTOP
+        && (signal.code() == signals::SI_KERNEL
+            || (signal.code() == signals::SI_USER && signal.sender() == std::process::id() as i32))
+}
 
 fn rot13_byte(b: u8) -> u8 {
     if b >= 65 && b <= 90 {
@@ -617,10 +1702,45 @@ fn rot13_byte(b: u8) -> u8 {
 
 #[cfg(test)]
 mod tests {
-    use crate::proctool::pcontroller::rot13_byte;
+    use crate::{
+        common::signals::{self, QueuedSignal, SIGINFO_SIZE},
+        proctool::pcontroller::{is_our_sigstop, rot13_byte, svc_search_priority},
+    };
 
     #[test]
     fn test_rot13_byte() {
         assert_eq!(rot13_byte(0x6f), 0x62);
     }
+
+    #[test]
+    fn test_svc_search_priority() {
+        assert_eq!(svc_search_priority("[vdso]"), 0);
+        assert_eq!(
+            svc_search_priority("/usr/lib/aarch64-linux-gnu/libc.so.6"),
+            1
+        );
+        assert_eq!(svc_search_priority("/lib/x86_64-linux-gnu/libc-2.31.so"), 1);
+        assert_eq!(svc_search_priority("/usr/lib/libcrypto.so.3"), 2);
+        assert_eq!(svc_search_priority("/home/user/countforever"), 2);
+    }
+
+    #[test]
+    fn test_is_our_sigstop() {
+        let make = |signal: i32, code: i32, sender: i32| {
+            let mut siginfo = vec![0u8; SIGINFO_SIZE];
+            siginfo[..4].copy_from_slice(&signal.to_le_bytes());
+            siginfo[8..12].copy_from_slice(&code.to_le_bytes());
+            siginfo[16..20].copy_from_slice(&sender.to_le_bytes());
+            QueuedSignal { siginfo }
+        };
+        let us = std::process::id() as i32;
+        assert!(is_our_sigstop(&make(libc::SIGSTOP, signals::SI_KERNEL, 0)));
+        assert!(is_our_sigstop(&make(libc::SIGSTOP, signals::SI_USER, us)));
+        assert!(!is_our_sigstop(&make(
+            libc::SIGSTOP,
+            signals::SI_USER,
+            us + 1
+        )));
+        assert!(!is_our_sigstop(&make(libc::SIGTERM, signals::SI_USER, us)));
+    }
 }
@@ -130,6 +130,55 @@ pub fn list_terminals() -> Result<()> {
     Ok(())
 }
 
+/// returns `pid` followed by all of its descendants, parents before children
+pub fn get_process_tree(pid: i32) -> Result<Vec<i32>> {
+    let all = get_all_process_info()?;
+    if !all.iter().any(|info| info.pid == pid) {
+        return Err(anyhow!("no such process: {}", pid));
+    }
+    Ok(order_parents_first(&[pid], &all, |_| true))
+}
+
+/// returns the members of a process group, parents before children
+pub fn get_process_group(pgid: i32) -> Result<Vec<i32>> {
+    let all = get_all_process_info()?;
+    let members: Vec<&ProcessInfo> = all.iter().filter(|info| info.pgid == pgid).collect();
+    if members.is_empty() {
+        return Err(anyhow!("no such process group: {}", pgid));
+    }
+
+    // members whose parent is outside the group, e.g. every command of a pipeline started by a
+    // shell
+    let mut roots: Vec<i32> = members
+        .iter()
+        .filter(|info| !members.iter().any(|parent| parent.pid == info.ppid))
+        .map(|info| info.pid)
+        .collect();
+    roots.sort();
+    Ok(order_parents_first(&roots, &all, |info| info.pgid == pgid))
+}
+
+/// walks down from `roots` breadth-first, only following children for which `include` is true
+fn order_parents_first<F: Fn(&ProcessInfo) -> bool>(
+    roots: &[i32],
+    all: &[ProcessInfo],
+    include: F,
+) -> Vec<i32> {
+    let mut r: Vec<i32> = roots.to_vec();
+    let mut i = 0;
+    while i < r.len() {
+        let mut children: Vec<i32> = all
+            .iter()
+            .filter(|info| info.ppid == r[i] && include(info))
+            .map(|info| info.pid)
+            .collect();
+        children.sort();
+        r.extend(children);
+        i += 1;
+    }
+    r
+}
+
 fn get_all_process_info() -> Result<Vec<ProcessInfo>> {
     let mut r = Vec::new();
     for entry_result in fs::read_dir("/proc")? {
@@ -161,12 +210,13 @@ fn get_all_process_info() -> Result<Vec<ProcessInfo>> {
 }
 
 pub struct ProcessInfo {
-    name: String,
-    pid: i32,
-    ppid: i32,
-    pgid: i32,
-    uid: u32,
-    tty: Option<String>,
+    pub name: String,
+    pub pid: i32,
+    pub ppid: i32,
+    pub pgid: i32,
+    pub sid: i32,
+    pub uid: u32,
+    pub tty: Option<String>,
 }
 
 pub fn get_process_info(pid: i32) -> Result<ProcessInfo> {
@@ -195,6 +245,7 @@ pub fn get_process_info(pid: i32) -> Result<ProcessInfo> {
     let name = attributes.get("Name").unwrap().clone();
     let ppid = attributes.get("PPid").unwrap().parse::<i32>().unwrap();
     let pgid = attributes.get("NSpgid").unwrap().parse::<i32>().unwrap();
+    let sid = attributes.get("NSsid").unwrap().parse::<i32>().unwrap();
     let uid = attributes
         .get("Uid")
         .unwrap()
@@ -208,6 +259,7 @@ pub fn get_process_info(pid: i32) -> Result<ProcessInfo> {
         pid,
         ppid,
         pgid,
+        sid,
         uid,
         tty,
     })
@@ -234,3 +286,40 @@ fn read_proc_stat(pid: i32) -> Result<StatFields> {
 struct StatFields {
     tty_nr: i32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{order_parents_first, ProcessInfo};
+
+    fn info(pid: i32, ppid: i32, pgid: i32) -> ProcessInfo {
+        ProcessInfo {
+            name: format!("p{}", pid),
+            pid,
+            ppid,
+            pgid,
+            sid: 1,
+            uid: 1000,
+            tty: None,
+        }
+    }
+
+    #[test]
+    fn test_order_parents_first() {
+        // a shell (10) running `a | b` (20, 21), where a has a child of its own (30)
+        let all = vec![
+            info(30, 20, 20),
+            info(21, 10, 20),
+            info(20, 10, 20),
+            info(10, 1, 10),
+            info(40, 1, 40),
+        ];
+        assert_eq!(
+            order_parents_first(&[10], &all, |_| true),
+            vec![10, 20, 21, 30]
+        );
+        assert_eq!(
+            order_parents_first(&[20, 21], &all, |info| info.pgid == 20),
+            vec![20, 21, 30]
+        );
+    }
+}
@@ -0,0 +1,445 @@
+// Where `freeze` and `checkpoint` keep their images: one directory, configurable with
+// PROCTOOL_STORE, holding `<name>.state` and a `<name>.json` describing it for every checkpoint.
+// proctool runs under sudo, so everything in it is handed back to the user who invoked us, and
+// only to them: the images hold all of a process's memory. The daemon runs with a umask of 0, so
+// the modes are given explicitly.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fmt::Write,
+    fs,
+    io::Write as _,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use nix::unistd;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    proctool::{cryogenics::Checkpoint, image},
+    teleclient::myprocfs,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CheckpointInfo {
+    pub name: String,
+    pub pid: i32,
+    pub command_line: Vec<String>,
+    /// seconds since the epoch
+    pub timestamp: u64,
+    /// of the image, in bytes
+    pub size: u64,
+    /// the checkpoint this one only stores the changes since
+    pub parent: Option<String>,
+    /// taken with `checkpoint`, which leaves the process running and resets soft-dirty tracking,
+    /// so that the next checkpoint of it can build on this one
+    pub live: bool,
+}
+
+impl CheckpointInfo {
+    /// describes a checkpoint of `pid` about to be taken; has to be called while the process is
+    /// still around
+    pub fn describe(name: &str, pid: i32) -> Result<Self> {
+        let command_line = myprocfs::get_command_line(pid)?
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg.strip_suffix(&[0]).unwrap_or(arg)).to_string())
+            .collect();
+        Ok(Self {
+            name: name.to_string(),
+            pid,
+            command_line,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            size: 0,
+            parent: None,
+            live: false,
+        })
+    }
+}
+
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    /// opens the store in PROCTOOL_STORE, or in `checkpoints` under `root` if that isn't set
+    pub fn from_env(root: &str) -> Result<Self> {
+        let dir = match std::env::var("PROCTOOL_STORE") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => Path::new(root).join("checkpoints"),
+        };
+        Self::open(dir)
+    }
+
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        if !dir.is_dir() {
+            // every directory created on the way has to be handed back, not just the store itself,
+            // or the user couldn't remove them later
+            let missing: Vec<&Path> = dir
+                .ancestors()
+                .take_while(|d| !d.as_os_str().is_empty() && !d.exists())
+                .collect();
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(&dir)
+                .map_err(|e| anyhow!("could not create {}: {}", dir.display(), e))?;
+            for created in missing {
+                give_to_invoking_user(created)?;
+            }
+        }
+        Ok(Self { dir })
+    }
+
+    /// where the image of the checkpoint `name` is
+    pub fn image_path(&self, name: &str) -> String {
+        self.dir
+            .join(Self::file_name(name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// the image's file name, which is how the images built on top of it refer to it
+    pub fn file_name(name: &str) -> String {
+        format!("{}.state", name)
+    }
+
+    fn info_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    /// returns `base`, or `base` with a number after it if that is taken
+    pub fn unique_name(&self, base: &str) -> String {
+        let mut name = base.to_string();
+        let mut n = 1;
+        while self.info_path(&name).exists() {
+            n += 1;
+            name = format!("{}-{}", base, n);
+        }
+        name
+    }
+
+    /// fails if `name` is taken or can't be a file name
+    pub fn check_name(&self, name: &str) -> Result<()> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            return Err(anyhow!("{:?} can't be used as a checkpoint name", name));
+        }
+        if self.info_path(name).exists() {
+            return Err(anyhow!("there already is a checkpoint named {}", name));
+        }
+        Ok(())
+    }
+
+    /// saves `checkpoint` as `info.name`, filling in its size; returns the updated `info`
+    pub fn save(
+        &self,
+        mut info: CheckpointInfo,
+        checkpoint: Checkpoint,
+        format: image::Format,
+    ) -> Result<CheckpointInfo> {
+        self.check_name(&info.name)?;
+
+        let path = self.image_path(&info.name);
+        image::save(&path, checkpoint, format)?;
+        give_to_invoking_user(Path::new(&path))?;
+        info.size = fs::metadata(&path)?.len();
+
+        let info_path = self.info_path(&info.name);
+        let json = serde_json::to_string_pretty(&info)?;
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&info_path)
+            .and_then(|mut f| f.write_all(json.as_bytes()))
+            .map_err(|e| anyhow!("could not write {}: {}", info_path.display(), e))?;
+        give_to_invoking_user(&info_path)?;
+        Ok(info)
+    }
+
+    /// returns every checkpoint, oldest first
+    pub fn list(&self) -> Result<Vec<CheckpointInfo>> {
+        let mut r = Vec::new();
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|e| anyhow!("could not read {}: {}", self.dir.display(), e))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new("json")) {
+                continue;
+            }
+            // one broken file shouldn't keep the rest from being listed or cleaned up
+            let info = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| {
+                    serde_json::from_str::<CheckpointInfo>(&json).map_err(|e| e.to_string())
+                });
+            match info {
+                Ok(info) => r.push(info),
+                Err(e) => eprintln!("warning: skipping {}: {}", path.display(), e),
+            }
+        }
+        r.sort_by(|a, b| (a.timestamp, &a.name).cmp(&(b.timestamp, &b.name)));
+        Ok(r)
+    }
+
+    pub fn get(&self, name: &str) -> Result<CheckpointInfo> {
+        self.list()?
+            .into_iter()
+            .find(|info| info.name == name)
+            .ok_or(anyhow!(
+                "no checkpoint named {} in {}",
+                name,
+                self.dir.display()
+            ))
+    }
+
+    /// returns the most recent checkpoint of `pid` that a new one could build on
+    pub fn latest_live(&self, pid: i32) -> Result<Option<CheckpointInfo>> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .rev()
+            .find(|info| info.pid == pid && info.live))
+    }
+
+    /// removes a checkpoint, unless another one was taken on top of it
+    pub fn remove(&self, name: &str) -> Result<()> {
+        let infos = self.list()?;
+        if !infos.iter().any(|info| info.name == name) {
+            return Err(anyhow!(
+                "no checkpoint named {} in {}",
+                name,
+                self.dir.display()
+            ));
+        }
+        if let Some(child) = infos
+            .iter()
+            .find(|info| info.parent.as_deref() == Some(name))
+        {
+            return Err(anyhow!(
+                "{} is needed by {}, which was taken on top of it",
+                name,
+                child.name
+            ));
+        }
+        self.delete(name)
+    }
+
+    /// keeps the `keep` most recent checkpoints of each process and whatever they were taken on
+    /// top of, and removes the rest; returns the names of the removed checkpoints
+    pub fn prune(&self, keep: usize) -> Result<Vec<String>> {
+        let infos = self.list()?;
+
+        let mut kept: Vec<&str> = Vec::new();
+        let mut newer: HashMap<i32, usize> = HashMap::new();
+        for info in infos.iter().rev() {
+            let n = newer.entry(info.pid).or_default();
+            if *n < keep {
+                kept.push(&info.name);
+            }
+            *n += 1;
+        }
+        // an incremental checkpoint is useless without its parents
+        let mut i = 0;
+        while i < kept.len() {
+            let parent = infos
+                .iter()
+                .find(|info| info.name == kept[i])
+                .and_then(|info| info.parent.as_deref());
+            if let Some(parent) = parent {
+                if !kept.contains(&parent) {
+                    kept.push(parent);
+                }
+            }
+            i += 1;
+        }
+
+        let mut removed = Vec::new();
+        for info in infos.iter() {
+            if !kept.contains(&info.name.as_str()) {
+                self.delete(&info.name)?;
+                removed.push(info.name.clone());
+            }
+        }
+        Ok(removed)
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        let path = self.image_path(name);
+        fs::remove_file(&path).map_err(|e| anyhow!("could not remove {}: {}", path, e))?;
+        fs::remove_file(self.info_path(name))?;
+        Ok(())
+    }
+}
+
+/// hands `path` to the user who ran us with sudo, if anyone did
+fn give_to_invoking_user(path: &Path) -> Result<()> {
+    let id = |var| {
+        std::env::var(var)
+            .ok()
+            .and_then(|id| id.parse::<u32>().ok())
+    };
+    if let (Some(uid), Some(gid)) = (id("SUDO_UID"), id("SUDO_GID")) {
+        unistd::chown(
+            path,
+            Some(unistd::Uid::from_raw(uid)),
+            Some(unistd::Gid::from_raw(gid)),
+        )
+        .map_err(|e| anyhow!("could not chown {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+pub fn format_list(infos: &[CheckpointInfo]) -> String {
+    let mut r = String::new();
+    writeln!(
+        r,
+        "{:<20} {:>8} {:<23} {:>12}  COMMAND",
+        "NAME", "PID", "TAKEN", "SIZE"
+    )
+    .unwrap();
+    for info in infos {
+        let mut name = info.name.clone();
+        if let Some(parent) = &info.parent {
+            write!(name, " (on {})", parent).unwrap();
+        }
+        writeln!(
+            r,
+            "{:<20} {:>8} {:<23} {:>12}  {}",
+            name,
+            info.pid,
+            format_timestamp(info.timestamp),
+            info.size,
+            info.command_line.join(" ")
+        )
+        .unwrap();
+    }
+    r
+}
+
+/// formats seconds since the epoch as a UTC date and time
+fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // civil_from_days() from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proctool::image::tests::make_process;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_store(test: &str) -> Store {
+        let dir = std::env::temp_dir().join(format!("proctool-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Store::open(dir).unwrap()
+    }
+
+    fn add(store: &Store, name: &str, pid: i32, timestamp: u64, parent: Option<&str>) {
+        let info = CheckpointInfo {
+            name: name.to_string(),
+            pid,
+            command_line: vec!["test".to_string()],
+            timestamp,
+            size: 0,
+            parent: parent.map(|p| p.to_string()),
+            live: true,
+        };
+        let checkpoint = Checkpoint::new(parent.map(Store::file_name), vec![make_process(pid, 1)]);
+        let format = image::Format::Binary(image::Compression::None);
+        store.save(info, checkpoint, format).unwrap();
+    }
+
+    fn names(store: &Store) -> Vec<String> {
+        store
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|info| info.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_save_and_list() {
+        let store = temp_store("list");
+        add(&store, "b", 100, 2, None);
+        add(&store, "a", 100, 1, None);
+        assert_eq!(names(&store), vec!["a", "b"]);
+        assert_eq!(store.unique_name("a"), "a-2");
+
+        let info = store.get("b").unwrap();
+        assert!(info.size > 0);
+        assert_eq!(
+            image::load(&store.image_path("b")).unwrap().processes[0].pid,
+            100
+        );
+        assert_eq!(store.latest_live(100).unwrap().unwrap().name, "b");
+        assert!(store.latest_live(200).unwrap().is_none());
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&store.dir), 0o700);
+        assert_eq!(mode(Path::new(&store.image_path("b"))), 0o600);
+        assert_eq!(mode(&store.info_path("b")), 0o600);
+
+        fs::write(store.dir.join("broken.json"), "{").unwrap();
+        assert_eq!(names(&store), vec!["a", "b"]);
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_keeps_parents() {
+        let store = temp_store("remove");
+        add(&store, "base", 100, 1, None);
+        add(&store, "incremental", 100, 2, Some("base"));
+        assert!(store.remove("base").is_err());
+        store.remove("incremental").unwrap();
+        store.remove("base").unwrap();
+        assert!(names(&store).is_empty());
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let store = temp_store("prune");
+        add(&store, "old", 100, 1, None);
+        add(&store, "base", 100, 2, None);
+        add(&store, "incremental", 100, 3, Some("base"));
+        add(&store, "other", 200, 1, None);
+
+        let removed = store.prune(1).unwrap();
+        assert_eq!(removed, vec!["old"]);
+        assert_eq!(names(&store), vec!["other", "base", "incremental"]);
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1709210096), "2024-02-29 12:34:56 UTC");
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use clap::Parser;
 
-use process_magic::common::httpapi;
+use process_magic::common::{arch::MachineInfo, httpapi};
 use process_magic::teleclient::ptrace;
 
 #[derive(Parser, Debug)]
@@ -14,15 +14,22 @@ fn main() -> Result<()> {
     let args = Args::parse();
 
     let tracer = ptrace::Tracer::seize_and_interrupt(args.pid)?;
-    let gp_register_data = tracer.get_general_purpose_registers()?;
-    let fp_register_data = tracer.get_floating_point_registers()?;
+    let registers = tracer.get_general_purpose_registers()?;
+    let fp_registers = tracer.get_floating_point_registers()?;
+    let signals = tracer.get_signal_state()?;
+    let signal_mask = tracer.get_signal_mask()?;
+    let thread_pending_signals = tracer.get_thread_pending_signals()?;
     let memory_maps = tracer.read_memory()?;
 
     let client = reqwest::blocking::Client::new();
     let request = httpapi::TeleforkApiRequest {
-        gp_register_data,
-        fp_register_data,
+        machine: MachineInfo::current()?,
+        registers: registers.into(),
+        fp_register_data: fp_registers.into(),
         memory_maps,
+        signals,
+        signal_mask,
+        thread_pending_signals,
     };
     let response: httpapi::TeleforkApiResponse = client
         .post("http://localhost:8000/telefork")
@@ -31,7 +38,10 @@ fn main() -> Result<()> {
         .json()?;
 
     if !response.success {
-        eprintln!("error: remote call was not successful");
+        eprintln!(
+            "error: remote call was not successful: {}",
+            response.error.unwrap_or("<no details>".to_string())
+        );
         std::process::exit(1);
     }
 
@@ -1,11 +1,15 @@
 use core::fmt;
-use std::fs::File;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader, Read, Seek};
+use std::os::unix::fs::MetadataExt;
 
 use anyhow::{anyhow, Result};
 use nix::unistd;
 use serde::{Deserialize, Serialize};
 
+use crate::common::elf;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MemoryMap {
     pub base_address: u64,
@@ -15,7 +19,132 @@ pub struct MemoryMap {
     pub writable: bool,
     pub executable: bool,
     pub private: bool,
+    /// offset into the backing file, if any
+    pub offset: u64,
+    pub inode: u64,
+    /// set if the region was saved by reference to `label` rather than by content
+    pub file: Option<FileBacking>,
+    // which parts of the region are stored in `data`, back-to-back; everything else is zero, or
+    // comes from `file` if it is set
+    pub runs: Vec<PageRun>,
     pub data: Vec<u8>,
+    /// in an incremental checkpoint, pages the process dropped since the parent checkpoint, which
+    /// are zero or come from `file` again rather than holding what the parent saved
+    #[serde(default)]
+    pub holes: Vec<PageRun>,
+}
+
+/// enough about a mapped file to tell whether it is still the one the process had mapped
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileBacking {
+    /// `st_dev` and `st_ino`, which change when the file is replaced, whatever its mtime says
+    pub dev: u64,
+    pub ino: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    /// FNV-1a hash of the mapped part of the file
+    pub hash: u64,
+    /// GNU build-id of the file in hex, if it is an ELF file with one
+    pub build_id: Option<String>,
+}
+
+/// a run of consecutive pages in a `MemoryMap` that are not all zero
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PageRun {
+    /// from the start of the region
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl MemoryMap {
+    /// returns (address, bytes) for each run of pages stored in `data`
+    pub fn populated_runs(&self) -> Vec<(u64, &[u8])> {
+        let mut r = Vec::new();
+        let mut start = 0;
+        for run in self.runs.iter() {
+            let end = start + run.len as usize;
+            r.push((self.base_address + run.offset, &self.data[start..end]));
+            start = end;
+        }
+        r
+    }
+
+    /// makes sure `runs` describe `data`, as `populated_runs` relies on, for a region that came
+    /// from an image or over the network
+    pub fn check_runs(&self, page_size: u64) -> Result<()> {
+        for run in self.runs.iter().chain(self.holes.iter()) {
+            let end = run.offset.checked_add(run.len);
+            let aligned = |x: u64| x.checked_rem(page_size) == Some(0);
+            if !aligned(run.offset) || !aligned(run.len) || end > Some(self.size) {
+                return Err(anyhow!(
+                    "{} has a bad run of {:#x} byte(s) at offset {:#x}",
+                    self,
+                    run.len,
+                    run.offset
+                ));
+            }
+        }
+        let total: u64 = self.runs.iter().map(|run| run.len).sum();
+        if total != self.data.len() as u64 {
+            return Err(anyhow!(
+                "{} has {} byte(s) of data but its runs add up to {}",
+                self,
+                self.data.len(),
+                total
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn is_file_backed(&self) -> bool {
+        self.inode != 0 && self.label.starts_with('/') && !self.label.ends_with(" (deleted)")
+    }
+
+    /// [vdso], [vvar] and the like, which the kernel maps into every process itself
+    pub fn is_kernel_provided(&self) -> bool {
+        self.label == "[vdso]" || self.label.starts_with("[vvar") || self.label == "[vsyscall]"
+    }
+}
+
+impl FileBacking {
+    pub fn read(memory_map: &MemoryMap) -> Result<Self> {
+        let metadata = fs::metadata(&memory_map.label)?;
+        if metadata.ino() != memory_map.inode {
+            return Err(anyhow!(
+                "inode is {} but the mapped file had inode {}",
+                metadata.ino(),
+                memory_map.inode
+            ));
+        }
+
+        Ok(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            hash: hash_file_range(&memory_map.label, memory_map.offset, memory_map.size)?,
+            build_id: elf::read_build_id(&memory_map.label).unwrap_or(None),
+        })
+    }
+
+    /// returns an error if the file behind `memory_map` is not the one that was frozen
+    pub fn verify(&self, memory_map: &MemoryMap) -> Result<()> {
+        let metadata = fs::metadata(&memory_map.label)
+            .map_err(|e| anyhow!("{} is no longer accessible: {}", memory_map.label, e))?;
+        if metadata.dev() != self.dev || metadata.ino() != self.ino {
+            return Err(anyhow!("{} has been replaced", memory_map.label));
+        }
+        if metadata.mtime() != self.mtime || metadata.mtime_nsec() != self.mtime_nsec {
+            return Err(anyhow!("{} has been modified", memory_map.label));
+        }
+
+        let hash = hash_file_range(&memory_map.label, memory_map.offset, memory_map.size)?;
+        if hash != self.hash {
+            return Err(anyhow!("contents of {} have changed", memory_map.label));
+        }
+
+        Ok(())
+    }
 }
 
 pub fn get_command_line(pid: i32) -> Result<Vec<Vec<u8>>> {
@@ -54,18 +183,82 @@ pub fn read_memory_maps(pid: i32) -> Result<Vec<MemoryMap>> {
 }
 
 pub fn populate_memory(pid: unistd::Pid, maps: &mut Vec<MemoryMap>) -> Result<()> {
+    populate(pid, maps, false, None)
+}
+
+/// like `populate_memory`, but file-backed regions are saved by reference (see `FileBacking`) and
+/// only the pages the process has modified are read
+///
+/// only useful if the state will be restored on the same machine, where the files still exist
+pub fn populate_memory_by_reference(pid: unistd::Pid, maps: &mut [MemoryMap]) -> Result<()> {
+    populate(pid, maps, true, None)
+}
+
+/// like `populate_memory_by_reference`, but for the regions `in_parent` returns true for, only
+/// the pages written to since the last call to `clear_soft_dirty` are read
+///
+/// the other pages are expected to be filled in from the parent checkpoint on thaw
+pub fn populate_memory_incremental(
+    pid: unistd::Pid,
+    maps: &mut [MemoryMap],
+    in_parent: &dyn Fn(&MemoryMap) -> bool,
+) -> Result<()> {
+    populate(pid, maps, true, Some(in_parent))
+}
+
+/// resets the soft-dirty bit of every page, so that the next call to
+/// `populate_memory_incremental` only sees the pages written to after this
+///
+/// see Documentation/admin-guide/mm/soft-dirty.rst
+pub fn clear_soft_dirty(pid: unistd::Pid) -> Result<()> {
+    let path = format!("/proc/{}/clear_refs", pid);
+    fs::write(&path, "4").map_err(|e| anyhow!("could not write to {}: {}", path, e))
+}
+
+fn populate(
+    pid: unistd::Pid,
+    maps: &mut [MemoryMap],
+    by_reference: bool,
+    in_parent: Option<&dyn Fn(&MemoryMap) -> bool>,
+) -> Result<()> {
     let path = format!("/proc/{}/mem", pid);
     let mut file = File::open(&path)?;
+    let page_size = procfs::page_size() as usize;
+    let mut pagemap = if by_reference {
+        Some(File::open(format!("/proc/{}/pagemap", pid))?)
+    } else {
+        None
+    };
 
     for memory_map in maps.iter_mut() {
         // [vvar] is special data used by the vDSO which for reasons unknown cannot be read via procfs
         // further discussion:
         //   - https://lwn.net/Articles/615809/
         //   - https://stackoverflow.com/questions/42730260/
-        if !memory_map.readable || memory_map.label == "[vvar]" {
+        // newer kernels split it into [vvar] and [vvar_vclock]
+        if !memory_map.readable || memory_map.label.starts_with("[vvar") {
             continue;
         }
 
+        if let (Some(pagemap), Some(in_parent)) = (pagemap.as_mut(), in_parent) {
+            if in_parent(memory_map) {
+                populate_soft_dirty(&mut file, pagemap, memory_map, page_size)?;
+                continue;
+            }
+        }
+
+        if let Some(pagemap) = pagemap.as_mut() {
+            if memory_map.is_file_backed() {
+                match populate_by_reference(&mut file, pagemap, memory_map, page_size) {
+                    Ok(()) => continue,
+                    Err(e) => eprintln!(
+                        "warning: saving {} by content instead of by reference: {}",
+                        memory_map.label, e
+                    ),
+                }
+            }
+        }
+
         file.seek(std::io::SeekFrom::Start(memory_map.base_address))
             .map_err(|e| {
                 anyhow!(
@@ -89,16 +282,263 @@ pub fn populate_memory(pid: unistd::Pid, maps: &mut Vec<MemoryMap>) -> Result<()
             eprintln!("error: {}", e);
             continue;
         }
-        memory_map.data = buf;
+
+        let (runs, data) = sparsify(&buf, page_size);
+        memory_map.runs = runs;
+        memory_map.data = data;
+    }
+
+    Ok(())
+}
+
+/// splits `buf` into runs of pages that are not all zero, returning the runs and their contents
+/// concatenated
+pub fn sparsify(buf: &[u8], page_size: usize) -> (Vec<PageRun>, Vec<u8>) {
+    let mut runs: Vec<PageRun> = Vec::new();
+    let mut data = Vec::new();
+
+    for (i, page) in buf.chunks(page_size).enumerate() {
+        if page.iter().all(|b| *b == 0) {
+            continue;
+        }
+
+        push_page(&mut runs, &mut data, (i * page_size) as u64, page);
+    }
+
+    (runs, data)
+}
+
+fn push_page(runs: &mut Vec<PageRun>, data: &mut Vec<u8>, offset: u64, page: &[u8]) {
+    match runs.last_mut() {
+        Some(run) if run.offset + run.len == offset => run.len += page.len() as u64,
+        _ => runs.push(PageRun {
+            offset,
+            len: page.len() as u64,
+        }),
+    }
+    data.extend_from_slice(page);
+}
+
+// from Documentation/admin-guide/mm/pagemap.rst
+const PAGEMAP_PRESENT: u64 = 1 << 63;
+const PAGEMAP_SWAPPED: u64 = 1 << 62;
+const PAGEMAP_FILE_OR_SHARED_ANON: u64 = 1 << 61;
+const PAGEMAP_SOFT_DIRTY: u64 = 1 << 55;
+
+fn populate_by_reference(
+    mem: &mut File,
+    pagemap: &mut File,
+    memory_map: &mut MemoryMap,
+    page_size: usize,
+) -> Result<()> {
+    let backing = FileBacking::read(memory_map)?;
+
+    // writes to a shared mapping go straight to the file, so there is nothing else to save
+    if memory_map.private {
+        let entries = read_pagemap(pagemap, memory_map, page_size)?;
+        // once a private page is written to, copy-on-write replaces it with an anonymous page,
+        // which is exactly the set of pages we can't get back from the file
+        read_pages(mem, memory_map, &entries, page_size, |entry| {
+            is_resident(entry) && entry & PAGEMAP_FILE_OR_SHARED_ANON == 0
+        })?;
+    } else {
+        memory_map.runs = Vec::new();
+        memory_map.data = Vec::new();
     }
 
+    memory_map.file = Some(backing);
     Ok(())
 }
 
+fn populate_soft_dirty(
+    mem: &mut File,
+    pagemap: &mut File,
+    memory_map: &mut MemoryMap,
+    page_size: usize,
+) -> Result<()> {
+    let entries = read_pagemap(pagemap, memory_map, page_size)?;
+    // a page that was dropped (MADV_DONTNEED, or the region was mapped again) isn't soft-dirty,
+    // but what the parent saved for it is no longer right either
+    if !memory_map.is_file_backed() {
+        memory_map.holes = select_pages(&entries, page_size, |entry| !is_resident(entry));
+        return read_pages(mem, memory_map, &entries, page_size, |entry| {
+            is_resident(entry) && entry & PAGEMAP_SOFT_DIRTY != 0
+        });
+    }
+
+    match FileBacking::read(memory_map) {
+        Ok(backing) => memory_map.file = Some(backing),
+        Err(e) => {
+            // as in `populate`, but only what is in memory can be read, dirty or not, since the
+            // parent's pages may have come from the file
+            eprintln!(
+                "warning: saving {} by content instead of by reference: {}",
+                memory_map.label, e
+            );
+            memory_map.holes = select_pages(&entries, page_size, |entry| !is_resident(entry));
+            return read_pages(mem, memory_map, &entries, page_size, is_resident);
+        }
+    }
+    if memory_map.private {
+        memory_map.holes = select_pages(&entries, page_size, |entry| {
+            !is_resident(entry) || entry & PAGEMAP_FILE_OR_SHARED_ANON != 0
+        });
+        read_pages(mem, memory_map, &entries, page_size, |entry| {
+            is_resident(entry)
+                && entry & PAGEMAP_SOFT_DIRTY != 0
+                && entry & PAGEMAP_FILE_OR_SHARED_ANON == 0
+        })?;
+    }
+    Ok(())
+}
+
+/// returns the runs of pages whose pagemap entry matches `select`
+fn select_pages<F: Fn(u64) -> bool>(entries: &[u64], page_size: usize, select: F) -> Vec<PageRun> {
+    let mut runs: Vec<PageRun> = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if !select(*entry) {
+            continue;
+        }
+
+        let offset = (i * page_size) as u64;
+        match runs.last_mut() {
+            Some(run) if run.offset + run.len == offset => run.len += page_size as u64,
+            _ => runs.push(PageRun {
+                offset,
+                len: page_size as u64,
+            }),
+        }
+    }
+    runs
+}
+
+/// stores the pages of `memory_map` whose pagemap entry matches `select`
+fn read_pages<F: Fn(u64) -> bool>(
+    mem: &mut File,
+    memory_map: &mut MemoryMap,
+    entries: &[u64],
+    page_size: usize,
+    select: F,
+) -> Result<()> {
+    let mut runs = Vec::new();
+    let mut data = Vec::new();
+    let mut page = vec![0u8; page_size];
+    for (i, entry) in entries.iter().enumerate() {
+        if !select(*entry) {
+            continue;
+        }
+
+        let offset = (i * page_size) as u64;
+        mem.seek(std::io::SeekFrom::Start(memory_map.base_address + offset))?;
+        mem.read_exact(&mut page)?;
+        push_page(&mut runs, &mut data, offset, &page);
+    }
+
+    memory_map.runs = runs;
+    memory_map.data = data;
+    Ok(())
+}
+
+fn is_resident(entry: u64) -> bool {
+    entry & (PAGEMAP_PRESENT | PAGEMAP_SWAPPED) != 0
+}
+
+/// returns the runs and data of `top` laid over those of `base`, page by page, leaving out the
+/// pages of `base` that `top` has holes at
+///
+/// both must describe the same region
+pub fn overlay_runs(
+    base: &MemoryMap,
+    top: &MemoryMap,
+    page_size: usize,
+) -> (Vec<PageRun>, Vec<u8>) {
+    let mut pages = BTreeMap::new();
+    insert_pages(&mut pages, base, page_size);
+    for hole in top.holes.iter() {
+        pages.retain(|offset, _| *offset < hole.offset || *offset - hole.offset >= hole.len);
+    }
+    insert_pages(&mut pages, top, page_size);
+
+    let mut runs = Vec::new();
+    let mut data = Vec::new();
+    for (offset, page) in pages {
+        push_page(&mut runs, &mut data, offset, page);
+    }
+    (runs, data)
+}
+
+/// adds each page stored in `memory_map` to `pages`, by offset into the region
+fn insert_pages<'a>(
+    pages: &mut BTreeMap<u64, &'a [u8]>,
+    memory_map: &'a MemoryMap,
+    page_size: usize,
+) {
+    for (addr, bytes) in memory_map.populated_runs() {
+        for (i, page) in bytes.chunks(page_size).enumerate() {
+            pages.insert(
+                addr - memory_map.base_address + (i * page_size) as u64,
+                page,
+            );
+        }
+    }
+}
+
+/// returns the raw 64-bit pagemap entry for each page in `memory_map`
+fn read_pagemap(pagemap: &mut File, memory_map: &MemoryMap, page_size: usize) -> Result<Vec<u64>> {
+    let npages = memory_map.size as usize / page_size;
+    let first_page = memory_map.base_address / page_size as u64;
+    pagemap.seek(std::io::SeekFrom::Start(first_page * 8))?;
+
+    let mut buf = vec![0u8; npages * 8];
+    pagemap
+        .read_exact(&mut buf)
+        .map_err(|e| anyhow!("unable to read pagemap: {}", e))?;
+    Ok(buf
+        .chunks(8)
+        .map(|entry| u64::from_le_bytes(entry.try_into().unwrap()))
+        .collect())
+}
+
+/// FNV-1a over `len` bytes of the file at `path` starting at `offset` (or up to the end of the
+/// file, if it is shorter)
+fn hash_file_range(path: &str, offset: u64, len: u64) -> Result<u64> {
+    let mut file = File::open(path)?;
+    file.seek(std::io::SeekFrom::Start(offset))?;
+    let mut reader = BufReader::new(file.take(len));
+
+    let mut hash = FNV_OFFSET_BASIS;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        hash = fnv1a(hash, buf);
+        let n = buf.len();
+        reader.consume(n);
+    }
+    Ok(hash)
+}
+
+pub const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+pub fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
 fn parse_map_line(line: &str) -> Result<MemoryMap> {
     let parts: Vec<&str> = line.splitn(6, char::is_whitespace).collect();
     let byte_range = parts[0];
     let permissions = parts[1];
+    let offset =
+        u64::from_str_radix(parts[2], 16).map_err(|e| anyhow!("could not parse offset: {}", e))?;
+    let inode = parts[4]
+        .parse::<u64>()
+        .map_err(|e| anyhow!("could not parse inode: {}", e))?;
     let label = parts[5];
 
     let (base_address, size) = parse_byte_range(byte_range)?;
@@ -112,7 +552,12 @@ fn parse_map_line(line: &str) -> Result<MemoryMap> {
         writable,
         executable,
         private,
+        offset,
+        inode,
+        file: None,
+        runs: Vec::new(),
         data: Vec::new(),
+        holes: Vec::new(),
     })
 }
 
@@ -168,7 +613,10 @@ impl fmt::Display for MemoryMap {
 
 #[cfg(test)]
 mod tests {
-    use super::parse_map_line;
+    use super::{
+        fnv1a, overlay_runs, parse_map_line, sparsify, FileBacking, PageRun, FNV_OFFSET_BASIS,
+    };
+    use std::{fs, os::unix::fs::MetadataExt};
 
     #[test]
     fn test_parse_memory_map_line() {
@@ -179,6 +627,141 @@ mod tests {
         assert!(!memory_map.writable);
         assert!(memory_map.executable);
         assert!(memory_map.private);
+        assert_eq!(memory_map.offset, 0);
+        assert_eq!(memory_map.inode, 298576);
         assert_eq!(memory_map.label, "/usr/lib/aarch64-linux-gnu/libc.so.6");
+        assert!(memory_map.is_file_backed());
+
+        let memory_map =
+            parse_map_line("ffffa3b6e000-ffffa3b70000 rw-p 001a0000 fc:00 298576   /usr/lib/aarch64-linux-gnu/libc.so.6\n").unwrap();
+        assert_eq!(memory_map.offset, 0x1a0000);
+
+        let memory_map = parse_map_line(
+            "aaaad6fe2000-aaaad7003000 rw-p 00000000 00:00 0                          [heap]\n",
+        )
+        .unwrap();
+        assert!(!memory_map.is_file_backed());
+    }
+
+    #[test]
+    fn test_file_replaced() {
+        let dir = std::env::temp_dir().join(format!("proctool-backing-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lib.so");
+        fs::write(&path, [1; 0x1000]).unwrap();
+
+        let line = format!(
+            "7f0000000000-7f0000001000 r--p 00000000 fc:00 {} {}\n",
+            fs::metadata(&path).unwrap().ino(),
+            path.display()
+        );
+        let memory_map = parse_map_line(&line).unwrap();
+        let backing = FileBacking::read(&memory_map).unwrap();
+        backing.verify(&memory_map).unwrap();
+
+        // the same contents and mtime, but a different file
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        let other = dir.join("lib.so.new");
+        fs::write(&other, [1; 0x1000]).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&other)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        fs::rename(&other, &path).unwrap();
+        assert!(backing.verify(&memory_map).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        // reference values from the FNV test suite
+        assert_eq!(fnv1a(FNV_OFFSET_BASIS, b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(FNV_OFFSET_BASIS, b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(FNV_OFFSET_BASIS, b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn test_sparsify() {
+        let mut buf = vec![0u8; 4096 * 5];
+        buf[4096] = 1;
+        buf[4096 * 2 + 100] = 2;
+        buf[4096 * 4 + 4095] = 3;
+
+        let (runs, data) = sparsify(&buf, 4096);
+        assert_eq!(
+            runs,
+            vec![
+                PageRun {
+                    offset: 4096,
+                    len: 4096 * 2
+                },
+                PageRun {
+                    offset: 4096 * 4,
+                    len: 4096
+                },
+            ]
+        );
+        assert_eq!(data.len(), 4096 * 3);
+        assert_eq!(data[0], 1);
+        assert_eq!(data[4096 + 100], 2);
+        assert_eq!(data[4096 * 3 - 1], 3);
+
+        let (runs, data) = sparsify(&vec![0u8; 4096 * 2], 4096);
+        assert!(runs.is_empty());
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_overlay_runs() {
+        let mut base =
+            parse_map_line("aaaad6fe2000-aaaad6fe6000 rw-p 00000000 00:00 0 [heap]").unwrap();
+        let mut top =
+            parse_map_line("aaaad6fe2000-aaaad6fe6000 rw-p 00000000 00:00 0 [heap]").unwrap();
+
+        // base has pages 0 and 1, top rewrites page 1 and adds page 3
+        let mut buf = vec![0u8; 4096 * 4];
+        buf[..4096 * 2].fill(1);
+        (base.runs, base.data) = sparsify(&buf, 4096);
+        buf.fill(0);
+        buf[4096..4096 * 2].fill(2);
+        buf[4096 * 3..].fill(3);
+        (top.runs, top.data) = sparsify(&buf, 4096);
+
+        let (runs, data) = overlay_runs(&base, &top, 4096);
+        assert_eq!(
+            runs,
+            vec![
+                PageRun {
+                    offset: 0,
+                    len: 4096 * 2
+                },
+                PageRun {
+                    offset: 4096 * 3,
+                    len: 4096
+                },
+            ]
+        );
+        assert_eq!(data[0], 1);
+        assert_eq!(data[4096], 2);
+        assert_eq!(data[4096 * 2], 3);
+
+        // and page 0 has been dropped since
+        top.holes = vec![PageRun {
+            offset: 0,
+            len: 4096,
+        }];
+        let (runs, data) = overlay_runs(&base, &top, 4096);
+        assert_eq!(
+            runs[0],
+            PageRun {
+                offset: 4096,
+                len: 4096
+            }
+        );
+        assert_eq!(data.len(), 4096 * 2);
+        assert_eq!(data[0], 2);
     }
 }
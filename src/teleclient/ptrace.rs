@@ -1,12 +1,16 @@
-use std::mem::MaybeUninit;
-
 use anyhow::{anyhow, Result};
 use nix::sys::ptrace as nix_ptrace;
 use nix::sys::wait::WaitPidFlag;
 use nix::unistd::Pid;
-use syscalls::Sysno;
 
-use crate::teleclient::myprocfs::MemoryMap;
+use crate::{
+    common::{
+        arch::{self, FpRegisters, Registers},
+        signals::{self, QueuedSignal, SignalState},
+    },
+    proctool::pcontroller::ProcessController,
+    teleclient::myprocfs::MemoryMap,
+};
 
 use super::myprocfs;
 
@@ -25,47 +29,42 @@ impl Tracer {
         Ok(Self { pid })
     }
 
-    pub fn get_general_purpose_registers(&self) -> Result<Vec<u8>> {
-        self.get_registers(libc::NT_PRSTATUS)
+    pub fn get_general_purpose_registers(&self) -> Result<Registers> {
+        arch::get_registers(self.pid)
     }
 
-    pub fn get_floating_point_registers(&self) -> Result<Vec<u8>> {
-        self.get_registers(libc::NT_PRFPREG)
+    pub fn get_floating_point_registers(&self) -> Result<FpRegisters> {
+        arch::get_fp_registers(self.pid)
     }
 
-    pub fn get_registers(&self, kind: libc::c_int) -> Result<Vec<u8>> {
-        // adapted from https://github.com/facebookexperimental/reverie/blob/852e08e75ddcd0ca3f5ea0ded7e60491051ffb76/safeptrace/src/lib.rs#L515
-
-        // `regs` will be initialized by called ptrace(). We need this instead of just `libc::iovec`
-        // so the compiler can tell us the size of `libc::user_regs_struct`, which is processor-
-        // dependent.
-        let mut regs = MaybeUninit::<libc::user_regs_struct>::uninit();
-        let mut iov = libc::iovec {
-            iov_base: regs.as_mut_ptr() as *mut libc::c_void,
-            iov_len: core::mem::size_of_val(&regs),
-        };
-
-        // TODO: also need to copy other kinds of registers besides NT_PRSTATUS
-        unsafe {
-            syscalls::syscall!(
-                Sysno::ptrace,
-                libc::PTRACE_GETREGSET,
-                self.pid.as_raw(),
-                kind,
-                // `*mut _` lets the compiler figure out the proper type here
-                &mut iov as *mut _
-            )
-        }
-        .map_err(|e| anyhow!("PTRACE_GETREGSET failed: {}", e))?;
+    /// returns the signals this thread blocks
+    pub fn get_signal_mask(&self) -> Result<u64> {
+        self.controller().get_signal_mask()
+    }
 
-        let mut r = Vec::new();
-        for i in 0..iov.iov_len as isize {
-            let p = iov.iov_base as *mut u8;
-            let v = unsafe { *p.offset(i) };
-            r.push(v);
-        }
+    /// returns the signals queued for this thread in particular, which `get_signal_state` leaves
+    /// out
+    pub fn get_thread_pending_signals(&self) -> Result<Vec<QueuedSignal>> {
+        self.controller().get_pending_signals(false)
+    }
 
-        Ok(r)
+    pub fn get_signal_state(&self) -> Result<SignalState> {
+        let controller = self.controller();
+
+        // reading the actions injects syscalls, which a pending signal mustn't go off in the
+        // middle of; as in `cryogenics::capture`
+        let mask = controller.get_signal_mask()?;
+        let result = controller
+            .set_signal_mask(signals::ALL_SIGNALS)
+            .and_then(|_| {
+                let pending = controller.get_pending_signals(true)?;
+                let actions = controller.get_signal_actions()?;
+                // this controller is thrown away, so whatever it had to inject goes with it
+                controller.remove_svc_page()?;
+                Ok(SignalState { actions, pending })
+            });
+        controller.set_signal_mask(mask)?;
+        result
     }
 
     pub fn read_memory(&self) -> Result<Vec<MemoryMap>> {
@@ -76,6 +75,13 @@ impl Tracer {
 
         Ok(memory_maps)
     }
+
+    fn controller(&self) -> ProcessController {
+        // we already hold the ptrace attachment, so the controller must leave it alone
+        let mut controller = ProcessController::new(self.pid);
+        controller.detach_on_drop = false;
+        controller
+    }
 }
 
 impl Drop for Tracer {
@@ -3,7 +3,13 @@ use rocket::serde::json::Json;
 use rocket::Config;
 use rocket::{data::Limits, http::Status};
 
-use process_magic::{common::httpapi, teleserver};
+use process_magic::{
+    common::{
+        arch::{FpRegisters, MachineInfo, Registers},
+        httpapi,
+    },
+    teleserver,
+};
 
 #[macro_use]
 extern crate rocket;
@@ -13,23 +19,68 @@ fn telefork_route(
     request: Json<httpapi::TeleforkApiRequest>,
 ) -> (Status, Json<httpapi::TeleforkApiResponse>) {
     println!("handling request");
-    if let Err(e) = teleserver::spawn::spawn_process(
-        &request.gp_register_data,
-        &request.fp_register_data,
-        &request.memory_maps,
-    ) {
+    let compatibility =
+        MachineInfo::current().and_then(|host| request.machine.check_compatible(&host));
+    if let Err(e) = compatibility {
+        eprintln!("error: rejecting incompatible request: {}", e);
+        return (
+            Status::BadRequest,
+            Json(httpapi::TeleforkApiResponse::error(e.to_string())),
+        );
+    }
+
+    let registers = Registers::try_from(request.registers.clone()).and_then(|registers| {
+        let fp_registers = FpRegisters::try_from(request.fp_register_data.clone())?;
+        Ok((registers, fp_registers))
+    });
+    let (registers, fp_registers) = match registers {
+        Ok(registers) => registers,
+        Err(e) => {
+            eprintln!("error: invalid register data: {}", e);
+            return (
+                Status::BadRequest,
+                Json(httpapi::TeleforkApiResponse::error(e.to_string())),
+            );
+        }
+    };
+
+    let page_size = request.machine.page_size;
+    let runs = request
+        .memory_maps
+        .iter()
+        .try_for_each(|memory_map| memory_map.check_runs(page_size));
+    if let Err(e) = runs {
+        eprintln!("error: invalid memory data: {}", e);
+        return (
+            Status::BadRequest,
+            Json(httpapi::TeleforkApiResponse::error(e.to_string())),
+        );
+    }
+
+    let signals = request.signals.check().and_then(|_| {
+        request
+            .thread_pending_signals
+            .iter()
+            .try_for_each(|signal| signal.check())
+    });
+    if let Err(e) = signals {
+        eprintln!("error: invalid signal data: {}", e);
+        return (
+            Status::BadRequest,
+            Json(httpapi::TeleforkApiResponse::error(e.to_string())),
+        );
+    }
+
+    if let Err(e) = teleserver::spawn::spawn_process(&registers, &fp_registers, &request) {
         eprintln!("error: {}", e);
         return (
             Status::InternalServerError,
-            Json(httpapi::TeleforkApiResponse { success: false }),
+            Json(httpapi::TeleforkApiResponse::error(e.to_string())),
         );
     }
     println!("handling request done");
 
-    (
-        Status::Ok,
-        Json(httpapi::TeleforkApiResponse { success: true }),
-    )
+    (Status::Ok, Json(httpapi::TeleforkApiResponse::ok()))
 }
 
 #[launch]